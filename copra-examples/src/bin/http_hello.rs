@@ -4,7 +4,7 @@ extern crate env_logger;
 extern crate futures;
 extern crate tokio_core;
 
-use copra::{Controller, MethodError, ServerBuilder, ServiceRegistry};
+use copra::{Controller, ErrorCode, MethodError, ServerBuilder, ServiceRegistry};
 use copra::protocol::http::HttpStatus;
 use futures::future::{self, FutureResult};
 use std::mem::replace;
@@ -45,7 +45,10 @@ impl HelloService for Hello {
 
                 future::ok((HelloResponse::new(), controller))
             }
-            Err(_) => future::err(MethodError::UnknownError),
+            Err(e) => {
+                let error = MethodError::new(ErrorCode::Codec, "body is not utf-8");
+                future::err(error.with_source(e))
+            }
         }
     }
 }
@@ -97,9 +97,22 @@ impl<'a> MetricStub<'a> {
             super::benchmark::Empty,
             super::benchmark::Empty,
         >,
+    > {
+        self.metric_with_options(msg, ::copra::channel::CallOptions::default())
+    }
+
+    pub fn metric_with_options(
+        &'a self, 
+        msg: super::benchmark::Empty,
+        options: ::copra::channel::CallOptions,
+    ) -> ::copra::stub::StubFuture<
+        ::copra::codec::ProtobufCodec<
+            super::benchmark::Empty,
+            super::benchmark::Empty,
+        >,
     > {
         self.metric_wrapper
-            .call((msg, "Metric".to_string(), "metric".to_string()))
+            .call((msg, "Metric".to_string(), "metric".to_string()), options)
     }
 }
 
@@ -238,9 +251,22 @@ impl<'a> PressureStub<'a> {
             super::benchmark::StringMessage,
             super::benchmark::StringMessage,
         >,
+    > {
+        self.echo_with_options(msg, ::copra::channel::CallOptions::default())
+    }
+
+    pub fn echo_with_options(
+        &'a self, 
+        msg: super::benchmark::StringMessage,
+        options: ::copra::channel::CallOptions,
+    ) -> ::copra::stub::StubFuture<
+        ::copra::codec::ProtobufCodec<
+            super::benchmark::StringMessage,
+            super::benchmark::StringMessage,
+        >,
     > {
         self.echo_wrapper
-            .call((msg, "Pressure".to_string(), "echo".to_string()))
+            .call((msg, "Pressure".to_string(), "echo".to_string()), options)
     }
 
     pub fn process(
@@ -251,8 +277,21 @@ impl<'a> PressureStub<'a> {
             super::benchmark::Empty,
             super::benchmark::PressureRequest,
         >,
+    > {
+        self.process_with_options(msg, ::copra::channel::CallOptions::default())
+    }
+
+    pub fn process_with_options(
+        &'a self, 
+        msg: super::benchmark::PressureRequest,
+        options: ::copra::channel::CallOptions,
+    ) -> ::copra::stub::StubFuture<
+        ::copra::codec::ProtobufCodec<
+            super::benchmark::Empty,
+            super::benchmark::PressureRequest,
+        >,
     > {
         self.process_wrapper
-            .call((msg, "Pressure".to_string(), "process".to_string()))
+            .call((msg, "Pressure".to_string(), "process".to_string()), options)
     }
 }
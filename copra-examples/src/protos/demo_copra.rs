@@ -140,9 +140,22 @@ impl<'a> DemoStub<'a> {
             super::demo::GreetMessage,
             super::demo::GreetMessage,
         >,
+    > {
+        self.greet_to_with_options(msg, ::copra::channel::CallOptions::default())
+    }
+
+    pub fn greet_to_with_options(
+        &'a self, 
+        msg: super::demo::GreetMessage,
+        options: ::copra::channel::CallOptions,
+    ) -> ::copra::stub::StubFuture<
+        ::copra::codec::ProtobufCodec<
+            super::demo::GreetMessage,
+            super::demo::GreetMessage,
+        >,
     > {
         self.greet_to_wrapper
-            .call((msg, "Demo".to_string(), "greet_to".to_string()))
+            .call((msg, "Demo".to_string(), "greet_to".to_string()), options)
     }
 
     pub fn is_prime(
@@ -153,8 +166,21 @@ impl<'a> DemoStub<'a> {
             super::demo::PrimeResponse,
             super::demo::PrimeRequest,
         >,
+    > {
+        self.is_prime_with_options(msg, ::copra::channel::CallOptions::default())
+    }
+
+    pub fn is_prime_with_options(
+        &'a self, 
+        msg: super::demo::PrimeRequest,
+        options: ::copra::channel::CallOptions,
+    ) -> ::copra::stub::StubFuture<
+        ::copra::codec::ProtobufCodec<
+            super::demo::PrimeResponse,
+            super::demo::PrimeRequest,
+        >,
     > {
         self.is_prime_wrapper
-            .call((msg, "Demo".to_string(), "is_prime".to_string()))
+            .call((msg, "Demo".to_string(), "is_prime".to_string()), options)
     }
 }
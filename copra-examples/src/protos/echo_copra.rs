@@ -140,9 +140,22 @@ impl<'a> EchoStub<'a> {
             super::echo::EchoResponse,
             super::echo::EchoRequest,
         >,
+    > {
+        self.echo_with_options(msg, ::copra::channel::CallOptions::default())
+    }
+
+    pub fn echo_with_options(
+        &'a self, 
+        msg: super::echo::EchoRequest,
+        options: ::copra::channel::CallOptions,
+    ) -> ::copra::stub::StubFuture<
+        ::copra::codec::ProtobufCodec<
+            super::echo::EchoResponse,
+            super::echo::EchoRequest,
+        >,
     > {
         self.echo_wrapper
-            .call((msg, "Echo".to_string(), "echo".to_string()))
+            .call((msg, "Echo".to_string(), "echo".to_string()), options)
     }
 
     pub fn rev_echo(
@@ -153,8 +166,21 @@ impl<'a> EchoStub<'a> {
             super::echo::EchoResponse,
             super::echo::EchoRequest,
         >,
+    > {
+        self.rev_echo_with_options(msg, ::copra::channel::CallOptions::default())
+    }
+
+    pub fn rev_echo_with_options(
+        &'a self, 
+        msg: super::echo::EchoRequest,
+        options: ::copra::channel::CallOptions,
+    ) -> ::copra::stub::StubFuture<
+        ::copra::codec::ProtobufCodec<
+            super::echo::EchoResponse,
+            super::echo::EchoRequest,
+        >,
     > {
         self.rev_echo_wrapper
-            .call((msg, "Echo".to_string(), "rev_echo".to_string()))
+            .call((msg, "Echo".to_string(), "rev_echo".to_string()), options)
     }
 }
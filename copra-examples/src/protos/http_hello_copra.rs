@@ -140,9 +140,22 @@ impl<'a> HelloStub<'a> {
             super::http_hello::HelloResponse,
             super::http_hello::HelloRequest,
         >,
+    > {
+        self.hello_general_with_options(msg, ::copra::channel::CallOptions::default())
+    }
+
+    pub fn hello_general_with_options(
+        &'a self, 
+        msg: super::http_hello::HelloRequest,
+        options: ::copra::channel::CallOptions,
+    ) -> ::copra::stub::StubFuture<
+        ::copra::codec::ProtobufCodec<
+            super::http_hello::HelloResponse,
+            super::http_hello::HelloRequest,
+        >,
     > {
         self.hello_general_wrapper
-            .call((msg, "Hello".to_string(), "hello_general".to_string()))
+            .call((msg, "Hello".to_string(), "hello_general".to_string()), options)
     }
 
     pub fn hello_to(
@@ -153,8 +166,21 @@ impl<'a> HelloStub<'a> {
             super::http_hello::HelloResponse,
             super::http_hello::HelloRequest,
         >,
+    > {
+        self.hello_to_with_options(msg, ::copra::channel::CallOptions::default())
+    }
+
+    pub fn hello_to_with_options(
+        &'a self, 
+        msg: super::http_hello::HelloRequest,
+        options: ::copra::channel::CallOptions,
+    ) -> ::copra::stub::StubFuture<
+        ::copra::codec::ProtobufCodec<
+            super::http_hello::HelloResponse,
+            super::http_hello::HelloRequest,
+        >,
     > {
         self.hello_to_wrapper
-            .call((msg, "Hello".to_string(), "hello_to".to_string()))
+            .call((msg, "Hello".to_string(), "hello_to".to_string()), options)
     }
 }
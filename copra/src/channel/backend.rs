@@ -1,52 +1,633 @@
 use futures::{Async, Future, Poll, Stream};
+use futures::future::Either;
 use futures::stream::FuturesUnordered;
 use futures::sync::oneshot;
-use tokio_core::reactor::Handle;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use tokio_service::Service;
+use tokio_timer::Timer;
 
-use super::{ChannelReceiver, OneShotSender, RequestPackage};
-use load_balancer::LoadBalance;
+use super::{CallOptions, ChannelError, ChannelReceiver, OneShotReceiver, OneShotSender,
+            RequestPackage, ResponsePackage};
+use load_balancer::{now_usec, CallInfo, LoadBalance, SelectContext, ServerEndPort, ServerId};
+use naming::{ServerListStream, ServerNode};
+use service::ErrorCode;
+use stub::meta_to_error;
 
 use super::{FeedbackHandle, FeedbackReceiver};
+use super::circuit_breaker::{Breaker, CircuitBreaker};
+use super::connector::{ConnectionState, ConnectionWatch};
+use super::health::{HealthCheck, ServerHealth};
+use super::retry::RetryPolicy;
+
+type CallId = u64;
+
+type AttemptId = u32;
+
+type EventFuture = Box<Future<Item = Event, Error = ()>>;
+
+/// Connect to a server
+pub(crate) type Connect =
+    Box<Fn(&ServerNode) -> Box<Future<Item = ServerEndPort, Error = io::Error>>>;
+
+/// Channel wide settings used by the backend
+pub struct BackendConfig {
+    pub deadline: Option<Duration>,
+    pub max_retry: u32,
+    pub retry_policy: Box<RetryPolicy>,
+    pub backup_request: Option<Duration>,
+    pub health_check: HealthCheck,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    pub connection_watch: ConnectionWatch,
+}
+
+enum Event {
+    Response(CallId, AttemptId, ServerId, io::Result<ResponsePackage>),
+    Timeout(CallId),
+    Backup(CallId),
+    Connected(ServerNode, ServerId, io::Result<ServerEndPort>),
+    Probe(ServerNode, ServerId, Duration),
+    Probed(ServerNode, ServerId, Duration, io::Result<ServerEndPort>),
+    HalfOpen(ServerId),
+    /// The caller has dropped the future of the call
+    Abandoned(CallId),
+    Cancelled,
+}
+
+struct Attempt {
+    id: AttemptId,
+    server_id: ServerId,
+    addr: SocketAddr,
+    start_usec: u64,
+    _cancel: oneshot::Sender<()>,
+}
+
+/// A request that has not been answered yet
+///
+/// Dropping it cancels the outstanding attempts and the timers, use
+/// `ChannelBackend::finish` so that they are reported to the load balancer.
+struct PendingCall {
+    resp_sender: OneShotSender,
+    req: RequestPackage,
+    select_ctx: SelectContext,
+    retry_left: u32,
+    sent: AttemptId,
+    attempts: Vec<Attempt>,
+    /// When the request times out, told to the server by every attempt
+    expires_at: Option<Instant>,
+    _deadline: Option<oneshot::Sender<()>>,
+    _backup: Option<oneshot::Sender<()>>,
+}
+
+impl PendingCall {
+    fn finish(self, result: Result<(ResponsePackage, FeedbackHandle), ChannelError>) {
+        // the caller might have gone away just now
+        let _ = self.resp_sender.send(result);
+    }
+}
+
+/// Pass the result of a call on to the caller, or resolve to
+/// `Event::Abandoned` once the caller drops its future.
+struct Forward {
+    call_id: CallId,
+    result: OneShotReceiver,
+    caller: Option<OneShotSender>,
+}
+
+impl Future for Forward {
+    type Item = Event;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.result.poll() {
+            Ok(Async::Ready(result)) => {
+                let caller = self.caller.take().expect("poll a finished forward");
+                let _ = caller.send(result);
+                // nothing left for the backend to do
+                return Ok(Async::Ready(Event::Cancelled));
+            }
+            // the backend is gone
+            Err(_) => return Ok(Async::Ready(Event::Cancelled)),
+            Ok(Async::NotReady) => {}
+        }
+        let caller = self.caller.as_mut().expect("poll a finished forward");
+        match caller.poll_cancel() {
+            Ok(Async::Ready(())) => Ok(Async::Ready(Event::Abandoned(self.call_id))),
+            _ => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Wrap `fut` so that it resolves to `Event::Cancelled` once the returned
+/// sender is dropped.
+fn cancellable<F>(fut: F) -> (oneshot::Sender<()>, EventFuture)
+where
+    F: Future<Item = Event, Error = ()> + 'static,
+{
+    let (cancel_sender, cancel_recv) = oneshot::channel();
+    let fut = fut.select2(cancel_recv).then(|result| match result {
+        Ok(Either::A((event, _))) => Ok(event),
+        _ => Ok(Event::Cancelled),
+    });
+    (cancel_sender, Box::new(fut))
+}
+
+/// Milliseconds left before `expires_at`, rounded up so that the server does
+/// not give up earlier than the client.
+fn remaining_ms(expires_at: Instant) -> i32 {
+    let now = Instant::now();
+    if expires_at <= now {
+        // zero would mean no deadline
+        return 1;
+    }
+    let remaining = expires_at - now;
+    let sub_ms = (remaining.subsec_nanos() + 999_999) / 1_000_000;
+    let ms = remaining.as_secs() * 1000 + u64::from(sub_ms);
+    // the field is an int32
+    ms.min(0x7fff_ffff) as i32
+}
+
+/// Servers given by the naming service
+struct Membership {
+    updates: Option<ServerListStream>,
+    connect: Connect,
+    /// Servers in the latest list, including the ones being connected
+    servers: HashMap<ServerNode, ServerId>,
+    next_server_id: ServerId,
+}
 
 #[must_use = "Channel backend must be spawned in a reactor, otherwise no request will be sent"]
 pub struct ChannelBackend {
-    handle: Handle,
+    timer: Timer,
+    config: BackendConfig,
     lb: Box<LoadBalance>,
     recv: ChannelReceiver,
+    recv_closed: bool,
+    next_call_id: CallId,
+    calls: HashMap<CallId, PendingCall>,
+    events: FuturesUnordered<EventFuture>,
     feedbacks: FuturesUnordered<FeedbackReceiver>,
+    membership: Option<Membership>,
+    breakers: HashMap<ServerId, Breaker>,
+    /// Servers taken out by their circuit breakers
+    isolated: HashMap<ServerId, ServerEndPort>,
 }
 
 impl ChannelBackend {
-    pub fn new<L>(recv: ChannelReceiver, handle: Handle, lb: L) -> Self
-    where
-        L: LoadBalance + 'static,
-    {
+    pub fn new(
+        recv: ChannelReceiver,
+        timer: Timer,
+        config: BackendConfig,
+        lb: Box<LoadBalance>,
+    ) -> Self {
         ChannelBackend {
             recv,
-            handle,
-            lb: Box::new(lb) as Box<LoadBalance>,
+            timer,
+            config,
+            lb,
+            recv_closed: false,
+            next_call_id: 0,
+            calls: HashMap::new(),
+            events: FuturesUnordered::new(),
             feedbacks: FuturesUnordered::new(),
+            membership: None,
+            breakers: HashMap::new(),
+            isolated: HashMap::new(),
+        }
+    }
+
+    /// Apply the server lists from `updates` to the load balancer.
+    ///
+    /// `servers` are the ones already added to the load balancer, or passed
+    /// to `check_health`.
+    pub fn watch(
+        mut self,
+        updates: ServerListStream,
+        connect: Connect,
+        servers: HashMap<ServerNode, ServerId>,
+    ) -> Self {
+        let next_server_id = servers.values().max().map_or(0, |id| id + 1);
+        self.membership = Some(Membership {
+            updates: Some(updates),
+            connect,
+            servers,
+            next_server_id,
+        });
+        self
+    }
+
+    /// Check the servers that failed to connect until they recover.
+    pub fn check_health(mut self, servers: Vec<(ServerNode, ServerId)>) -> Self {
+        let backoff = self.config.health_check.initial_backoff;
+        for (server, id) in servers {
+            self.disconnected(server.addr);
+            self.schedule_probe(server, id, backoff);
+        }
+        self
+    }
+
+    /// Report a finished attempt to the load balancer and the circuit breaker.
+    fn feed_back(&mut self, id: ServerId, call_info: CallInfo) {
+        let open = match self.config.circuit_breaker {
+            Some(ref config) => self.breakers
+                .entry(id)
+                .or_insert_with(Breaker::new)
+                .feed_back(config, &call_info),
+            None => false,
+        };
+        self.lb.feed_back(id, call_info);
+        if open {
+            self.open_circuit(id);
+        }
+    }
+
+    /// Take a server out of the load balancer until its breaker is half-open.
+    fn open_circuit(&mut self, id: ServerId) {
+        let end_port = match self.lb.remove_server(id) {
+            Some(end_port) => end_port,
+            None => return,
+        };
+        let cooldown = self.config
+            .circuit_breaker
+            .as_ref()
+            .map(CircuitBreaker::get_cooldown)
+            .unwrap_or_default();
+        warn!(
+            "Circuit breaker of server {} is open for {:?}",
+            end_port.addr(),
+            cooldown
+        );
+        self.config
+            .health_check
+            .notify(end_port.addr(), ServerHealth::Unhealthy);
+        self.isolated.insert(id, end_port);
+        let half_open = self.timer.sleep(cooldown).then(move |result| {
+            if let Err(e) = result {
+                warn!("Circuit breaker timer failed: {}", e);
+            }
+            Ok(Event::HalfOpen(id))
+        });
+        self.events.push(Box::new(half_open));
+    }
+
+    /// Take a server out of the load balancer, and check it in the background
+    /// until it recovers.
+    fn eject(&mut self, id: ServerId) {
+        // no way to connect again
+        if self.membership.is_none() {
+            return;
+        }
+        let end_port = match self.lb.remove_server(id) {
+            Some(end_port) => end_port,
+            // already ejected by another failed request
+            None => return,
+        };
+        let server = ServerNode {
+            addr: end_port.addr(),
+            weight: end_port.weight(),
+        };
+        self.disconnected(server.addr);
+        let backoff = self.config.health_check.initial_backoff;
+        self.schedule_probe(server, id, backoff);
+    }
+
+    /// Report a server that cannot be connected.
+    fn disconnected(&self, addr: SocketAddr) {
+        self.config
+            .connection_watch
+            .set(addr, ConnectionState::Disconnected);
+        self.config
+            .health_check
+            .notify(addr, ServerHealth::Unhealthy);
+    }
+
+    fn schedule_probe(&mut self, server: ServerNode, id: ServerId, backoff: Duration) {
+        let probe = self.timer.sleep(backoff).then(move |result| {
+            if let Err(e) = result {
+                warn!("Health check timer failed: {}", e);
+            }
+            Ok(Event::Probe(server, id, backoff))
+        });
+        self.events.push(Box::new(probe));
+    }
+
+    /// Whether the server is still in the server list.
+    fn is_member(&self, server: &ServerNode, id: ServerId) -> bool {
+        match self.membership {
+            Some(ref membership) => membership.servers.get(server) == Some(&id),
+            None => false,
+        }
+    }
+
+    /// Remove the servers not in `list`, and connect to the new ones.
+    fn update_servers(&mut self, list: Vec<ServerNode>) {
+        let membership = match self.membership {
+            Some(ref mut membership) => membership,
+            None => return,
+        };
+        let list: HashSet<_> = list.into_iter().collect();
+
+        let removed: Vec<_> = membership
+            .servers
+            .keys()
+            .filter(|server| !list.contains(server))
+            .cloned()
+            .collect();
+        for server in removed {
+            let id = membership.servers.remove(&server).unwrap();
+            info!("Remove server {}", server.addr);
+            self.lb.remove_server(id);
+            self.config.connection_watch.remove(&server.addr);
+            self.isolated.remove(&id);
+            self.breakers.remove(&id);
+        }
+
+        for server in list {
+            if membership.servers.contains_key(&server) {
+                continue;
+            }
+            let id = membership.next_server_id;
+            membership.next_server_id += 1;
+            membership.servers.insert(server.clone(), id);
+
+            info!("Connect to new server {}", server.addr);
+            let fut = (membership.connect)(&server)
+                .then(move |result| Ok(Event::Connected(server, id, result)));
+            self.events.push(Box::new(fut));
         }
     }
 
-    fn spawn(&mut self, resp_sender: OneShotSender, req: RequestPackage) {
+    fn spawn(&mut self, caller: OneShotSender, req: RequestPackage, options: CallOptions) {
+        if caller.is_canceled() {
+            debug!("Request is dropped before it is sent");
+            return;
+        }
         trace!("Spawned a new rpc request.");
 
-        let (server_id, end_port) = self.lb.select_server();
-        let (fb_sender, fb_recv) = oneshot::channel();
-        let fut = end_port.call(req).then(move |result| {
-            let fb_handle = FeedbackHandle::new(server_id, fb_sender);
-            // TODO: Or maybe just ignore this error, for the rpc request might be cancelled.
-            resp_sender
-                .send(result.map(move |r| (r, fb_handle)))
-                .expect("The receiving end of the oneshot is dropped.");
+        let call_id = self.next_call_id;
+        self.next_call_id += 1;
+
+        let (resp_sender, result) = oneshot::channel();
+        self.events.push(Box::new(Forward {
+            call_id,
+            result,
+            caller: Some(caller),
+        }));
+
+        let timeout = options.get_timeout().or(self.config.deadline);
+        let expires_at = timeout.map(|timeout| Instant::now() + timeout);
+        let deadline = timeout.map(|timeout| self.set_timer(timeout, Event::Timeout(call_id)));
+        let backup = self.config
+            .backup_request
+            .map(|delay| self.set_timer(delay, Event::Backup(call_id)));
 
-            Ok(())
+        let call = PendingCall {
+            resp_sender,
+            req,
+            select_ctx: SelectContext {
+                request_code: options.get_request_code(),
+            },
+            retry_left: options.get_max_retry().unwrap_or(self.config.max_retry),
+            sent: 0,
+            attempts: Vec::new(),
+            expires_at,
+            _deadline: deadline,
+            _backup: backup,
+        };
+        self.send_attempt(call_id, call);
+    }
+
+    /// Fire `event` after `delay`, unless the returned sender is dropped.
+    fn set_timer(&mut self, delay: Duration, event: Event) -> oneshot::Sender<()> {
+        let timer = self.timer.sleep(delay).then(move |result| {
+            if let Err(e) = result {
+                warn!("Request timer failed: {}", e);
+            }
+            Ok(event)
+        });
+        let (cancel, fut) = cancellable(timer);
+        self.events.push(fut);
+        cancel
+    }
+
+    /// Send the request to a server chosen by the load balancer, and keep
+    /// track of the call.
+    ///
+    /// If no server is available, the call fails unless an earlier attempt is
+    /// still on the way.
+    fn send_attempt(&mut self, call_id: CallId, mut call: PendingCall) {
+        let attempt_id = call.sent;
+        call.sent += 1;
+
+        let (server_id, end_port) = match self.lb.select_server(&call.select_ctx) {
+            Some(selected) => selected,
+            None => {
+                warn!("No server is available for request {}", call_id);
+                if call.attempts.is_empty() {
+                    self.finish(call, Err(ChannelError::NoServerAvailable));
+                } else {
+                    self.calls.insert(call_id, call);
+                }
+                return;
+            }
+        };
+        let start_usec = now_usec();
+        let addr = end_port.addr();
+        let mut req = call.req.clone();
+        if let Some(expires_at) = call.expires_at {
+            req.0.set_timeout_ms(remaining_ms(expires_at));
+        }
+        let attempt = end_port
+            .call(req)
+            .then(move |result| Ok(Event::Response(call_id, attempt_id, server_id, result)));
+        let (cancel, fut) = cancellable(attempt);
+        call.attempts.push(Attempt {
+            id: attempt_id,
+            server_id,
+            addr,
+            start_usec,
+            _cancel: cancel,
         });
+        self.events.push(fut);
+        self.calls.insert(call_id, call);
+    }
+
+    /// Resolve the call, and report the attempts still on the way as cancelled.
+    fn finish(
+        &mut self,
+        call: PendingCall,
+        result: Result<(ResponsePackage, FeedbackHandle), ChannelError>,
+    ) {
+        for attempt in call.attempts.iter() {
+            self.feed_back(attempt.server_id, CallInfo::cancelled(attempt.start_usec));
+        }
+        call.finish(result);
+    }
+
+    fn retry(&mut self, call_id: CallId, mut call: PendingCall) {
+        call.retry_left -= 1;
+        debug!(
+            "Retry request {}, {} retries left",
+            call_id, call.retry_left
+        );
+        self.send_attempt(call_id, call);
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Response(call_id, attempt_id, server_id, result) => {
+                // the call might have been finished, ignore the late response
+                let mut call = match self.calls.remove(&call_id) {
+                    Some(call) => call,
+                    None => return,
+                };
+                let (addr, start_usec) = match call.attempts.iter().position(|a| a.id == attempt_id) {
+                    Some(idx) => {
+                        let attempt = call.attempts.remove(idx);
+                        (attempt.addr, attempt.start_usec)
+                    }
+                    // an attempt given up already, the call is still waiting
+                    None => {
+                        self.calls.insert(call_id, call);
+                        return;
+                    }
+                };
 
-        self.feedbacks.push(fb_recv);
-        self.handle.spawn(fut);
+                match result {
+                    Ok(resp) => {
+                        if let Some(e) = meta_to_error(&resp.0) {
+                            let policy = &self.config.retry_policy;
+                            if call.retry_left > 0 && policy.retry_on_method_error(&e) {
+                                self.feed_back(server_id, CallInfo::new(start_usec, Some(e)));
+                                return self.retry(call_id, call);
+                            }
+                        }
+                        let (fb_sender, fb_recv) = oneshot::channel();
+                        self.feedbacks.push(fb_recv);
+                        let handle = FeedbackHandle::new(server_id, start_usec, fb_sender);
+                        self.finish(call, Ok((resp, handle)));
+                    }
+                    Err(e) => {
+                        debug!("Request to server {} failed: {}", server_id, e);
+                        self.feed_back(
+                            server_id,
+                            CallInfo::new(start_usec, Some(ErrorCode::ConnectionFailed.into())),
+                        );
+                        // the connection is broken for good, stop sending
+                        // requests to it
+                        match self.config.connection_watch.state(&addr) {
+                            Some(ConnectionState::Reconnecting(_)) => {}
+                            _ => self.eject(server_id),
+                        }
+                        // another attempt is still on the way, wait for it
+                        if !call.attempts.is_empty() {
+                            self.calls.insert(call_id, call);
+                            return;
+                        }
+                        let e = match e.kind() {
+                            io::ErrorKind::NotConnected | io::ErrorKind::ConnectionAborted => {
+                                ChannelError::Disconnected(e)
+                            }
+                            _ => ChannelError::IoError(e),
+                        };
+                        let policy = &self.config.retry_policy;
+                        if call.retry_left > 0 && policy.retry_on_channel_error(&e) {
+                            self.retry(call_id, call);
+                        } else {
+                            self.finish(call, Err(e));
+                        }
+                    }
+                }
+            }
+            Event::Timeout(call_id) => {
+                if let Some(call) = self.calls.remove(&call_id) {
+                    for attempt in call.attempts.iter() {
+                        debug!("Request to server {} timed out", attempt.server_id);
+                        self.feed_back(
+                            attempt.server_id,
+                            CallInfo::new(attempt.start_usec, Some(ErrorCode::Timeout.into())),
+                        );
+                    }
+                    call.finish(Err(ChannelError::Timeout));
+                }
+            }
+            Event::Backup(call_id) => {
+                if let Some(call) = self.calls.remove(&call_id) {
+                    debug!("Request {} is not answered in time, send a backup request", call_id);
+                    self.send_attempt(call_id, call);
+                }
+            }
+            Event::Connected(server, id, result) => {
+                // the server might have been removed while connecting
+                if !self.is_member(&server, id) {
+                    return;
+                }
+                match result {
+                    Ok(end_port) => self.lb.add_server(id, end_port),
+                    Err(e) => {
+                        warn!("Failed to connect to server {}: {}", server.addr, e);
+                        self.disconnected(server.addr);
+                        let backoff = self.config.health_check.initial_backoff;
+                        self.schedule_probe(server, id, backoff);
+                    }
+                }
+            }
+            Event::Probe(server, id, backoff) => {
+                if !self.is_member(&server, id) {
+                    return;
+                }
+                let probe = {
+                    let membership = self.membership.as_ref().expect("no way to connect");
+                    self.config.health_check.probe(&server, &membership.connect)
+                };
+                let fut = probe.then(move |result| Ok(Event::Probed(server, id, backoff, result)));
+                self.events.push(Box::new(fut));
+            }
+            Event::Probed(server, id, backoff, result) => {
+                if !self.is_member(&server, id) {
+                    return;
+                }
+                match result {
+                    Ok(end_port) => {
+                        self.lb.add_server(id, end_port);
+                        self.breakers.remove(&id);
+                        self.config
+                            .health_check
+                            .notify(server.addr, ServerHealth::Healthy);
+                    }
+                    Err(e) => {
+                        debug!("Health check of server {} failed: {}", server.addr, e);
+                        let backoff = self.config.health_check.next_backoff(backoff);
+                        self.schedule_probe(server, id, backoff);
+                    }
+                }
+            }
+            Event::HalfOpen(id) => {
+                if let Some(end_port) = self.isolated.remove(&id) {
+                    info!("Circuit breaker of server {} is half-open", end_port.addr());
+                    self.config
+                        .health_check
+                        .notify(end_port.addr(), ServerHealth::Healthy);
+                    self.lb.add_server(id, end_port);
+                    if let Some(breaker) = self.breakers.get_mut(&id) {
+                        breaker.half_open();
+                    }
+                }
+            }
+            Event::Abandoned(call_id) => {
+                // dropping the attempts tells the servers to give up, no
+                // retry or backup request is sent
+                if let Some(call) = self.calls.remove(&call_id) {
+                    debug!("Request {} is dropped by the caller", call_id);
+                    for attempt in call.attempts.iter() {
+                        self.feed_back(attempt.server_id, CallInfo::cancelled(attempt.start_usec));
+                    }
+                }
+            }
+            Event::Cancelled => {}
+        }
     }
 }
 
@@ -55,18 +636,66 @@ impl Future for ChannelBackend {
     type Error = ();
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // check returned feedback info first, so that new requests are not
+        // sent to servers that have just been taken out
         loop {
-            // check returned feedback info
-            // TODO: error handling?
-            //       the oneshot channel might be broken (due to dropped sender).
-            if let Ok(Async::Ready(Some((server_id, call_info)))) = self.feedbacks.poll() {
-                self.lb.feed_back(server_id, call_info);
+            match self.feedbacks.poll() {
+                Ok(Async::Ready(Some((server_id, call_info)))) => {
+                    self.feed_back(server_id, call_info)
+                }
+                // the feedback handle is dropped without reporting
+                Err(_) => continue,
+                _ => break,
             }
-            // spawn new request
-            match try_ready!(self.recv.poll()) {
-                Some((resp_sender, req)) => self.spawn(resp_sender, req),
-                None => return Ok(Async::Ready(())),
+        }
+
+        // spawn new requests
+        while !self.recv_closed {
+            match self.recv.poll()? {
+                Async::Ready(Some((resp_sender, req, options))) => {
+                    self.spawn(resp_sender, req, options)
+                }
+                Async::Ready(None) => self.recv_closed = true,
+                Async::NotReady => break,
             }
         }
+
+        // apply changes of the server list
+        loop {
+            let updates = match self.membership {
+                Some(Membership {
+                    updates: Some(ref mut updates),
+                    ..
+                }) => updates.poll(),
+                _ => break,
+            };
+            match updates {
+                Ok(Async::Ready(Some(list))) => self.update_servers(list),
+                Ok(Async::Ready(None)) => {
+                    if let Some(ref mut membership) = self.membership {
+                        membership.updates = None;
+                    }
+                }
+                Ok(Async::NotReady) => break,
+                Err(e) => {
+                    // the stream keeps going, poll again to wait for the
+                    // next update
+                    warn!("Naming service error: {}", e);
+                    continue;
+                }
+            }
+        }
+
+        // handle finished attempts and timers, which might issue retries
+        while let Ok(Async::Ready(Some(event))) = self.events.poll() {
+            self.handle_event(event);
+        }
+
+        // keep running until all the pending requests are answered
+        if self.recv_closed && self.calls.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
     }
 }
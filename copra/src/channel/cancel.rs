@@ -0,0 +1,185 @@
+use bytes::Bytes;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures::stream::FuturesUnordered;
+use futures::sync::oneshot;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use tokio_proto::multiplex::RequestId;
+use tokio_service::Service;
+
+use message::RpcResponseMeta;
+use protocol::ClientMessage;
+use service::ErrorCode;
+
+use super::{RequestPackage, ResponsePackage};
+use super::connection::Connection;
+
+/// A request, along with the receiver that resolves once the caller gives up
+pub(crate) type CancellableRequest = (RequestPackage, oneshot::Receiver<()>);
+
+type Watch = Box<Future<Item = RequestId, Error = ()>>;
+
+/// Send `req` over `conn`.
+///
+/// If the returned future is dropped before the response comes back, the
+/// server is told to give up the request.
+pub(crate) fn call(
+    conn: &Connection,
+    req: RequestPackage,
+) -> Box<Future<Item = ResponsePackage, Error = io::Error>> {
+    let (watch, cancelled) = oneshot::channel();
+    let fut = conn.call((req, cancelled)).then(move |result| {
+        drop(watch);
+        result
+    });
+    Box::new(fut)
+}
+
+/// Transport that writes a cancel notice for the requests given up by the
+/// caller while on the way
+///
+/// Without `notify`, no notice is written. Either way, if a response can not
+/// be decoded, the requests on the way are answered with `ErrorCode::Codec`
+/// before the error is passed on, since the client would only tell them the
+/// connection is broken.
+pub struct CancelTransport<T> {
+    inner: T,
+    notify: bool,
+    in_flight: HashSet<RequestId>,
+    watches: FuturesUnordered<Watch>,
+    /// Cancel notices not written yet
+    notices: VecDeque<RequestId>,
+    /// The decode error, and the requests not told about it yet
+    undecodable: Option<(io::Error, Vec<RequestId>)>,
+}
+
+impl<T> fmt::Debug for CancelTransport<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CancelTransport")
+            .field("notify", &self.notify)
+            .field("in_flight", &self.in_flight.len())
+            .finish()
+    }
+}
+
+impl<T> CancelTransport<T> {
+    pub fn new(inner: T, notify: bool) -> Self {
+        CancelTransport {
+            inner,
+            notify,
+            in_flight: HashSet::new(),
+            watches: FuturesUnordered::new(),
+            notices: VecDeque::new(),
+            undecodable: None,
+        }
+    }
+}
+
+impl<T> CancelTransport<T>
+where
+    T: Sink<SinkItem = (RequestId, ClientMessage), SinkError = io::Error>,
+{
+    fn write_notices(&mut self) -> Poll<(), io::Error> {
+        while let Some(id) = self.notices.pop_front() {
+            if let AsyncSink::NotReady(_) = self.inner.start_send((id, ClientMessage::Cancel))? {
+                self.notices.push_front(id);
+                return Ok(Async::NotReady);
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<T> Stream for CancelTransport<T>
+where
+    T: Stream<Item = (RequestId, ResponsePackage), Error = io::Error>,
+{
+    type Item = (RequestId, ResponsePackage);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // the watches share the task of the transport, queue the notices
+        // here and write them on the next flush
+        while let Ok(Async::Ready(Some(id))) = self.watches.poll() {
+            if self.in_flight.remove(&id) {
+                debug!("Request {} is given up, tell the server", id);
+                self.notices.push_back(id);
+            }
+        }
+
+        if self.undecodable.is_none() {
+            match self.inner.poll() {
+                Ok(Async::Ready(frame)) => {
+                    if let Some((id, _)) = frame {
+                        self.in_flight.remove(&id);
+                    }
+                    return Ok(Async::Ready(frame));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::InvalidData {
+                        return Err(e);
+                    }
+                    let ids = self.in_flight.drain().collect();
+                    self.undecodable = Some((e, ids));
+                }
+            }
+        }
+
+        // answer the requests on the way, then fail the connection
+        let (e, mut ids) = self.undecodable.take().unwrap();
+        match ids.pop() {
+            Some(id) => {
+                let mut meta = RpcResponseMeta::new();
+                meta.set_error_code(ErrorCode::Codec.errno());
+                meta.set_error_text(e.to_string());
+                self.undecodable = Some((e, ids));
+                Ok(Async::Ready(Some((id, (meta, Bytes::new())))))
+            }
+            None => Err(e),
+        }
+    }
+}
+
+impl<T> Sink for CancelTransport<T>
+where
+    T: Sink<SinkItem = (RequestId, ClientMessage), SinkError = io::Error>,
+{
+    type SinkItem = (RequestId, CancellableRequest);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        if self.write_notices()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        let (id, ((meta, body), cancelled)) = item;
+        match self.inner
+            .start_send((id, ClientMessage::Request(meta, body)))?
+        {
+            AsyncSink::Ready => {
+                self.in_flight.insert(id);
+                if !self.notify {
+                    return Ok(AsyncSink::Ready);
+                }
+                // never sent to, resolves once the sender is dropped
+                self.watches.push(Box::new(cancelled.then(move |_| Ok(id))));
+                Ok(AsyncSink::Ready)
+            }
+            AsyncSink::NotReady((id, ClientMessage::Request(meta, body))) => {
+                Ok(AsyncSink::NotReady((id, ((meta, body), cancelled))))
+            }
+            AsyncSink::NotReady(_) => unreachable!(),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.write_notices());
+        self.inner.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.write_notices());
+        self.inner.close()
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use load_balancer::CallInfo;
+
+/// Stop sending requests to servers that keep failing
+///
+/// The breaker of a server watches the outcome of its recent requests. It
+/// opens when the ratio of failed requests, or the average latency, crosses
+/// the threshold, and the server is taken out of the load balancer. After a
+/// cooldown the breaker is half-open, and the server is put back: the next
+/// request decides whether the breaker closes, or opens again.
+///
+/// Only failures that hint at an unhealthy server count, see
+/// `CallInfo::is_server_failure`.
+///
+/// # Examples
+///
+/// ```
+/// use copra::channel::CircuitBreaker;
+/// use std::time::Duration;
+///
+/// let breaker = CircuitBreaker::new()
+///     .max_error_ratio(0.3)
+///     .max_latency(Duration::from_millis(500))
+///     .cooldown(Duration::from_secs(5));
+/// ```
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    window: usize,
+    min_requests: usize,
+    max_error_ratio: f64,
+    max_latency: Option<Duration>,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    /// Create a breaker with the default settings.
+    ///
+    /// It opens when half of the last 100 requests failed, and stays open
+    /// for a second.
+    pub fn new() -> Self {
+        CircuitBreaker {
+            window: 100,
+            min_requests: 20,
+            max_error_ratio: 0.5,
+            max_latency: None,
+            cooldown: Duration::from_secs(1),
+        }
+    }
+
+    /// Set how many recent requests are watched, and how many of them are
+    /// needed before the breaker can open.
+    ///
+    /// Default to 100 and 20.
+    pub fn window(mut self, window: usize, min_requests: usize) -> Self {
+        self.window = window.max(1);
+        self.min_requests = min_requests.max(1).min(self.window);
+        self
+    }
+
+    /// Open the breaker if the ratio of failed requests exceeds `ratio`.
+    ///
+    /// Default to 0.5.
+    pub fn max_error_ratio(mut self, ratio: f64) -> Self {
+        self.max_error_ratio = ratio;
+        self
+    }
+
+    /// Open the breaker if the average latency exceeds `latency`.
+    ///
+    /// Default to `None`, latency is not checked.
+    pub fn max_latency(mut self, latency: Duration) -> Self {
+        self.max_latency = Some(latency);
+        self
+    }
+
+    /// Set how long the breaker stays open.
+    ///
+    /// Default to 1 second.
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    pub(crate) fn get_cooldown(&self) -> Duration {
+        self.cooldown
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Outcome of a finished request
+#[derive(Clone, Copy, Debug)]
+struct Outcome {
+    failed: bool,
+    latency_usec: u64,
+}
+
+/// The breaker of a server
+#[derive(Debug)]
+pub(crate) struct Breaker {
+    state: State,
+    outcomes: VecDeque<Outcome>,
+}
+
+impl Breaker {
+    pub fn new() -> Self {
+        Breaker {
+            state: State::Closed,
+            outcomes: VecDeque::new(),
+        }
+    }
+
+    /// Record a finished request, and tell whether the breaker opens.
+    pub fn feed_back(&mut self, config: &CircuitBreaker, call_info: &CallInfo) -> bool {
+        // the outcome is unknown
+        if call_info.cancelled {
+            return false;
+        }
+        let outcome = Outcome {
+            failed: call_info.is_server_failure(),
+            latency_usec: call_info.elapsed_usec(),
+        };
+
+        match self.state {
+            // a late response of a request sent before the breaker opened
+            State::Open => false,
+            State::HalfOpen => {
+                if outcome.failed {
+                    self.open();
+                    true
+                } else {
+                    self.state = State::Closed;
+                    false
+                }
+            }
+            State::Closed => {
+                self.outcomes.push_back(outcome);
+                while self.outcomes.len() > config.window {
+                    self.outcomes.pop_front();
+                }
+                if self.should_open(config) {
+                    self.open();
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// Let requests through again, until the next one fails.
+    pub fn half_open(&mut self) {
+        self.state = State::HalfOpen;
+    }
+
+    fn open(&mut self) {
+        self.state = State::Open;
+        self.outcomes.clear();
+    }
+
+    fn should_open(&self, config: &CircuitBreaker) -> bool {
+        let len = self.outcomes.len();
+        if len < config.min_requests {
+            return false;
+        }
+        let failed = self.outcomes.iter().filter(|o| o.failed).count();
+        if failed as f64 / len as f64 > config.max_error_ratio {
+            return true;
+        }
+        match config.max_latency {
+            Some(max_latency) => {
+                let total: u64 = self.outcomes.iter().map(|o| o.latency_usec).sum();
+                let max_usec =
+                    max_latency.as_secs() * 1_000_000 + u64::from(max_latency.subsec_micros());
+                total / len as u64 > max_usec
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use load_balancer::now_usec;
+    use service::{ErrorCode, MethodError};
+
+    fn config() -> CircuitBreaker {
+        CircuitBreaker::new().window(10, 5)
+    }
+
+    fn finished(latency_usec: u64, error: Option<MethodError>) -> CallInfo {
+        CallInfo::new(now_usec() - latency_usec, error)
+    }
+
+    #[test]
+    fn open_on_errors() {
+        let config = config();
+        let mut breaker = Breaker::new();
+        for _ in 0..5 {
+            assert!(!breaker.feed_back(&config, &finished(1_000, None)));
+        }
+        for _ in 0..5 {
+            assert!(!breaker.feed_back(&config, &finished(1_000, Some(ErrorCode::Unknown.into()))));
+        }
+        // 6 out of the last 10 failed
+        assert!(breaker.feed_back(&config, &finished(1_000, Some(ErrorCode::Timeout.into()))));
+        // late responses do not open it again
+        assert!(!breaker.feed_back(&config, &finished(1_000, Some(ErrorCode::Timeout.into()))));
+    }
+
+    #[test]
+    fn wait_for_enough_requests() {
+        let config = config();
+        let mut breaker = Breaker::new();
+        for _ in 0..4 {
+            let failed = finished(1_000, Some(ErrorCode::Unknown.into()));
+            assert!(!breaker.feed_back(&config, &failed));
+        }
+        // cancelled requests do not count
+        assert!(!breaker.feed_back(&config, &CallInfo::cancelled(now_usec())));
+        assert!(breaker.feed_back(&config, &finished(1_000, Some(ErrorCode::Unknown.into()))));
+    }
+
+    #[test]
+    fn ignore_client_errors() {
+        let config = config();
+        let mut breaker = Breaker::new();
+        for _ in 0..10 {
+            assert!(!breaker.feed_back(&config, &finished(1_000, Some(ErrorCode::Codec.into()))));
+        }
+    }
+
+    #[test]
+    fn open_on_latency() {
+        let config = config().max_latency(Duration::from_millis(10));
+        let mut breaker = Breaker::new();
+        for _ in 0..4 {
+            assert!(!breaker.feed_back(&config, &finished(20_000, None)));
+        }
+        assert!(breaker.feed_back(&config, &finished(20_000, None)));
+    }
+
+    #[test]
+    fn half_open() {
+        let config = config();
+        let mut breaker = Breaker::new();
+        breaker.open();
+
+        // a failure opens it again at once
+        breaker.half_open();
+        assert!(breaker.feed_back(&config, &finished(1_000, Some(ErrorCode::Unknown.into()))));
+
+        // a success closes it, and it takes a full window to open it again
+        breaker.half_open();
+        assert!(!breaker.feed_back(&config, &finished(1_000, None)));
+        for _ in 0..4 {
+            let failed = finished(1_000, Some(ErrorCode::Unknown.into()));
+            assert!(!breaker.feed_back(&config, &failed));
+        }
+        assert!(breaker.feed_back(&config, &finished(1_000, Some(ErrorCode::Unknown.into()))));
+    }
+}
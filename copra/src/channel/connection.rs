@@ -0,0 +1,318 @@
+use futures::{future, Future};
+use std::cell::RefCell;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_proto::TcpClient;
+use tokio_proto::multiplex::ClientService;
+use tokio_service::Service;
+use tokio_timer::Timer;
+
+use load_balancer::ServerEndPort;
+use naming::ServerNode;
+use protocol::Protocol;
+
+use super::{MetaClientProtocol, RequestPackage, ResponsePackage};
+use super::cancel;
+use super::connector::{ConnectionState, ConnectionWatch, Connector, ReconnectPolicy};
+
+pub(crate) type Connection = ClientService<TcpStream, MetaClientProtocol>;
+
+type ResponseFuture = Box<Future<Item = ResponsePackage, Error = io::Error>>;
+
+/// How a channel connects to each server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionType {
+    /// All the requests are multiplexed on one connection
+    Single,
+    /// Requests are multiplexed on a fixed number of connections, each one
+    /// goes to the connection with the fewest requests in flight
+    Pooled(usize),
+    /// Every request gets a new connection, which is closed once the
+    /// response comes back
+    Short,
+}
+
+impl Default for ConnectionType {
+    fn default() -> Self {
+        ConnectionType::Single
+    }
+}
+
+/// How to connect to servers
+#[derive(Clone)]
+pub(crate) struct ConnectOptions {
+    pub connection_type: ConnectionType,
+    pub protocol: Protocol,
+    pub handle: Handle,
+    pub timer: Timer,
+    pub reconnect: Option<ReconnectPolicy>,
+    pub watch: ConnectionWatch,
+    /// Tell the server about the requests given up by the caller
+    pub notify_cancel: bool,
+}
+
+pub(crate) fn connect_one(
+    options: &ConnectOptions,
+    addr: SocketAddr,
+) -> Box<Future<Item = Connection, Error = io::Error>> {
+    let proto = MetaClientProtocol::new(&options.protocol, addr).notify_cancel(options.notify_cancel);
+    Box::new(TcpClient::new(proto).connect(&addr, &options.handle))
+}
+
+/// Connect to a server in the way given by `options`.
+pub(crate) fn connect(
+    options: &ConnectOptions,
+    server: &ServerNode,
+) -> Box<Future<Item = ServerEndPort, Error = io::Error>> {
+    let (addr, weight) = (server.addr, server.weight);
+    let fut: Box<Future<Item = ServerEndPort, Error = io::Error>> = match options.connection_type {
+        ConnectionType::Single => {
+            let options = options.clone();
+            Box::new(connect_one(&options, addr).map(move |conn| {
+                let connector = Connector::new(addr, conn, options);
+                ServerEndPort::from_service(addr, connector)
+            }))
+        }
+        ConnectionType::Pooled(size) => Box::new(
+            PooledService::connect(options.clone(), addr, size.max(1))
+                .map(move |pool| ServerEndPort::from_service(addr, pool)),
+        ),
+        ConnectionType::Short => {
+            let service = ShortService {
+                options: options.clone(),
+                addr,
+            };
+            // make sure the server can be reached
+            Box::new(
+                TcpStream::connect(&addr, &options.handle)
+                    .map(move |_| ServerEndPort::from_service(addr, service)),
+            )
+        }
+    };
+    let watch = options.watch.clone();
+    Box::new(fut.map(move |end_port| {
+        watch.set(addr, ConnectionState::Connected);
+        end_port.with_weight(weight)
+    }))
+}
+
+/// A connection in the pool
+#[derive(Default)]
+struct Slot {
+    conn: Option<Connection>,
+    connecting: bool,
+    in_flight: usize,
+    /// Bumped on every new connection, so that responses on a replaced
+    /// connection are not counted
+    generation: u64,
+}
+
+/// Gives the slot of a request back once the request is answered, or its
+/// future is dropped
+struct SlotGuard {
+    slots: Rc<RefCell<Vec<Slot>>>,
+    idx: usize,
+    generation: u64,
+}
+
+impl SlotGuard {
+    /// Drop the connection, which fails requests from now on.
+    fn broken(&self) {
+        let slot = &mut self.slots.borrow_mut()[self.idx];
+        if slot.generation == self.generation {
+            slot.conn = None;
+        }
+    }
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        let slot = &mut self.slots.borrow_mut()[self.idx];
+        if slot.generation == self.generation {
+            slot.in_flight -= 1;
+        }
+    }
+}
+
+/// Send requests over a pool of multiplexed connections
+///
+/// A connection that fails a request is dropped, and connected again in the
+/// background when the next request comes. Requests fail only if none of the
+/// connections is available.
+struct PooledService {
+    options: ConnectOptions,
+    addr: SocketAddr,
+    slots: Rc<RefCell<Vec<Slot>>>,
+}
+
+impl PooledService {
+    /// Open `size` connections, it is an error only if none of them can be
+    /// opened.
+    fn connect(
+        options: ConnectOptions,
+        addr: SocketAddr,
+        size: usize,
+    ) -> Box<Future<Item = Self, Error = io::Error>> {
+        let connects: Vec<_> = (0..size)
+            .map(|_| connect_one(&options, addr).then(Ok::<_, io::Error>))
+            .collect();
+        let fut = future::join_all(connects).and_then(move |results| {
+            let mut last_error = None;
+            let slots: Vec<_> = results
+                .into_iter()
+                .map(|result| match result {
+                    Ok(conn) => Slot {
+                        conn: Some(conn),
+                        ..Slot::default()
+                    },
+                    Err(e) => {
+                        last_error = Some(e);
+                        Slot::default()
+                    }
+                })
+                .collect();
+            if slots.iter().all(|slot| slot.conn.is_none()) {
+                return Err(last_error.unwrap());
+            }
+            Ok(PooledService {
+                options,
+                addr,
+                slots: Rc::new(RefCell::new(slots)),
+            })
+        });
+        Box::new(fut)
+    }
+
+    /// Connect the slots whose connections are dropped.
+    fn reconnect(&self) {
+        let mut slots = self.slots.borrow_mut();
+        for (idx, slot) in slots.iter_mut().enumerate() {
+            if slot.conn.is_some() || slot.connecting {
+                continue;
+            }
+            slot.connecting = true;
+            let weak = Rc::downgrade(&self.slots);
+            let addr = self.addr;
+            let fut = connect_one(&self.options, addr).then(move |result| {
+                // the pool is gone
+                let slots = match Weak::upgrade(&weak) {
+                    Some(slots) => slots,
+                    None => return Ok(()),
+                };
+                let slot = &mut slots.borrow_mut()[idx];
+                slot.connecting = false;
+                match result {
+                    Ok(conn) => {
+                        slot.conn = Some(conn);
+                        slot.in_flight = 0;
+                        slot.generation += 1;
+                    }
+                    Err(e) => warn!("Failed to reconnect to server {}: {}", addr, e),
+                }
+                Ok(())
+            });
+            self.options.handle.spawn(fut);
+        }
+    }
+}
+
+impl Service for PooledService {
+    type Request = RequestPackage;
+    type Response = ResponsePackage;
+    type Error = io::Error;
+    type Future = ResponseFuture;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        self.reconnect();
+
+        let picked = {
+            let mut slots = self.slots.borrow_mut();
+            let idx = slots
+                .iter()
+                .enumerate()
+                .filter(|&(_, slot)| slot.conn.is_some())
+                .min_by_key(|&(_, slot)| slot.in_flight)
+                .map(|(idx, _)| idx);
+            idx.map(|idx| {
+                let slot = &mut slots[idx];
+                slot.in_flight += 1;
+                (idx, slot.generation, slot.conn.clone().unwrap())
+            })
+        };
+        let (idx, generation, conn) = match picked {
+            Some(picked) => picked,
+            None => {
+                let e = io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("no connection to {} is available", self.addr),
+                );
+                return Box::new(future::err(e));
+            }
+        };
+
+        let guard = SlotGuard {
+            slots: self.slots.clone(),
+            idx,
+            generation,
+        };
+        let fut = cancel::call(&conn, req).then(move |result| {
+            if result.is_err() {
+                guard.broken();
+            }
+            result
+        });
+        Box::new(fut)
+    }
+}
+
+/// Send every request over a new connection
+struct ShortService {
+    options: ConnectOptions,
+    addr: SocketAddr,
+}
+
+impl Service for ShortService {
+    type Request = RequestPackage;
+    type Response = ResponsePackage;
+    type Error = io::Error;
+    type Future = ResponseFuture;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        // the connection is closed once the only request is answered
+        let fut = connect_one(&self.options, self.addr)
+            .and_then(move |conn| cancel::call(&conn, req));
+        Box::new(fut)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slot_given_back_on_drop() {
+        let slots = Rc::new(RefCell::new(vec![Slot::default()]));
+        let guard = |generation| {
+            slots.borrow_mut()[0].in_flight += 1;
+            SlotGuard {
+                slots: slots.clone(),
+                idx: 0,
+                generation,
+            }
+        };
+
+        let first = guard(0);
+        let second = guard(0);
+        drop(first);
+        assert_eq!(slots.borrow()[0].in_flight, 1);
+
+        // the count of a replaced connection starts over
+        slots.borrow_mut()[0].generation = 1;
+        slots.borrow_mut()[0].in_flight = 0;
+        drop(second);
+        assert_eq!(slots.borrow()[0].in_flight, 0);
+    }
+}
@@ -1,208 +1,465 @@
-use futures::{Async, Future, Poll};
+use futures::{future, Async, Future, Poll};
+use futures::sync::mpsc;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::fmt;
 use std::io::{self, ErrorKind, Read, Write};
 use std::mem;
 use std::net::SocketAddr;
-use tokio_core::reactor::Handle;
-use tokio_core::net::{TcpStream, TcpStreamNew};
+use std::rc::{Rc, Weak};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_core::net::TcpStream;
 use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_service::Service;
 
-enum State {
+use time::secs_f64;
+
+use super::{RequestPackage, ResponsePackage};
+use super::cancel;
+use super::connection::{connect_one, ConnectOptions, Connection};
+
+enum StreamState {
     Connected(TcpStream),
-    Connecting(TcpStreamNew),
     Disconnected,
 }
 
-impl fmt::Debug for State {
+impl fmt::Debug for StreamState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            State::Connected(ref tcp) => write!(f, "State::Connected({:?})", tcp),
-            State::Connecting(_) => write!(f, "State::Connecting"),
-            State::Disconnected => write!(f, "State::Disconnected"),
+            StreamState::Connected(ref tcp) => write!(f, "StreamState::Connected({:?})", tcp),
+            StreamState::Disconnected => write!(f, "StreamState::Disconnected"),
         }
     }
 }
 
+/// A TCP stream that reports a broken connection as an error
+///
+/// A transport that reaches the end of stream leaves the requests in flight
+/// waiting forever, so the end of stream is turned into an error as well.
+/// The stream is not reestablished here, see `Connector`.
 #[derive(Debug)]
-pub struct Connector {
+pub struct ClientStream {
     addr: SocketAddr,
-    state: State,
-    handle: Handle,
+    state: StreamState,
 }
 
-impl Connector {
-    pub fn from_stream(addr: SocketAddr, stream: TcpStream, handle: Handle) -> Self {
-        Connector {
+impl ClientStream {
+    pub fn new(addr: SocketAddr, stream: TcpStream) -> Self {
+        ClientStream {
             addr,
-            state: State::Connected(stream),
-            handle,
+            state: StreamState::Connected(stream),
         }
     }
 
-    pub fn poll_ready(&mut self) -> Poll<(), io::Error> {
-        match mem::replace(&mut self.state, State::Disconnected) {
-            State::Connected(io) => {
-                self.state = State::Connected(io);
-                Ok(Async::Ready(()))
-            }
-            State::Connecting(mut fut) => match fut.poll()? {
-                Async::Ready(io) => {
-                    self.state = State::Connected(io);
-                    Ok(Async::Ready(()))
-                }
-                Async::NotReady => {
-                    self.state = State::Connecting(fut);
-                    Ok(Async::NotReady)
-                }
-            },
-            State::Disconnected => unreachable!(),
-        }
-    }
-
-    fn reconnect(&mut self) {
-        let new = TcpStream::connect(&self.addr, &self.handle);
-        self.state = State::Connecting(new);
+    fn disconnected(&self) -> io::Error {
+        io::Error::new(
+            ErrorKind::NotConnected,
+            format!("connection to {} is broken", self.addr),
+        )
     }
-}
 
-impl Read for Connector {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        loop {
-            match mem::replace(&mut self.state, State::Disconnected) {
-                State::Connected(mut io) => {
-                    let r = io.read(buf);
-                    match r {
-                        Ok(_) => {
-                            self.state = State::Connected(io);
-                            return r;
-                        }
-                        Err(e) => {
-                            if e.kind() == ErrorKind::WouldBlock {
-                                self.state = State::Connected(io);
-                                return Err(e);
-                            }
-                            // TODO: elaborate err conditions.
-                            // Currently we assume all the errors except for Wouldblock
-                            // are caused by a broken connection.
-                        }
-                    };
-                }
-                State::Connecting(mut fut) => match fut.poll()? {
-                    Async::Ready(io) => {
-                        self.state = State::Connected(io);
-                    }
-                    Async::NotReady => {
-                        self.state = State::Connecting(fut);
-                        return Err(io::Error::new(
-                            ErrorKind::WouldBlock,
-                            "Waiting for connection to be established",
-                        ));
+    /// Run `f` on the stream, and drop the stream if it fails.
+    fn with_stream<F, T>(&mut self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut TcpStream) -> io::Result<T>,
+    {
+        match mem::replace(&mut self.state, StreamState::Disconnected) {
+            StreamState::Connected(mut io) => {
+                let r = f(&mut io);
+                match r {
+                    Err(ref e) if e.kind() != ErrorKind::WouldBlock => {
+                        // TODO: elaborate err conditions.
+                        // Currently we assume all the errors except for Wouldblock
+                        // are caused by a broken connection.
+                        warn!("Connection to {} is broken: {}", self.addr, e);
                     }
-                },
-                State::Disconnected => {
-                    self.reconnect();
+                    _ => self.state = StreamState::Connected(io),
                 }
+                r
             }
+            StreamState::Disconnected => Err(self.disconnected()),
         }
     }
 }
 
-impl Write for Connector {
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let addr = self.addr;
+        self.with_stream(|io| match io.read(buf) {
+            Ok(0) if !buf.is_empty() => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("connection closed by {}", addr),
+            )),
+            r => r,
+        })
+    }
+}
+
+impl Write for ClientStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        loop {
-            match mem::replace(&mut self.state, State::Disconnected) {
-                State::Connected(mut io) => {
-                    let r = io.write(buf);
-                    match r {
-                        Ok(_) => {
-                            self.state = State::Connected(io);
-                            return r;
-                        }
-                        Err(e) => {
-                            if e.kind() == ErrorKind::WouldBlock {
-                                self.state = State::Connected(io);
-                                return Err(e);
-                            }
-                        }
-                    };
-                }
-                State::Connecting(mut fut) => match fut.poll()? {
-                    Async::Ready(io) => {
-                        self.state = State::Connected(io);
-                    }
-                    Async::NotReady => {
-                        self.state = State::Connecting(fut);
-                        return Err(io::Error::new(
-                            ErrorKind::WouldBlock,
-                            "Waiting for connection to be established",
-                        ));
-                    }
-                },
-                State::Disconnected => {
-                    self.reconnect();
-                }
-            }
-        }
+        self.with_stream(|io| io.write(buf))
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        loop {
-            match mem::replace(&mut self.state, State::Disconnected) {
-                State::Connected(mut io) => {
-                    let r = io.flush();
-                    match r {
-                        Ok(_) => {
-                            self.state = State::Connected(io);
-                            return r;
-                        }
-                        Err(e) => {
-                            if e.kind() == ErrorKind::WouldBlock {
-                                self.state = State::Connected(io);
-                                return Err(e);
-                            }
-                        }
-                    };
-                }
-                State::Connecting(mut fut) => match fut.poll()? {
-                    Async::Ready(io) => {
-                        self.state = State::Connected(io);
-                    }
-                    Async::NotReady => {
-                        self.state = State::Connecting(fut);
-                        return Err(io::Error::new(
-                            ErrorKind::WouldBlock,
-                            "Waiting for connection to be established",
-                        ));
-                    }
-                },
-                State::Disconnected => {
-                    self.reconnect();
-                }
-            }
-        }
+        self.with_stream(|io| io.flush())
     }
 }
 
-impl AsyncRead for Connector {
+impl AsyncRead for ClientStream {
     // Ported from <TcpStream as AsyncRead>
     unsafe fn prepare_uninitialized_buffer(&self, _: &mut [u8]) -> bool {
         false
     }
 }
 
-impl AsyncWrite for Connector {
+impl AsyncWrite for ClientStream {
     fn shutdown(&mut self) -> Poll<(), io::Error> {
-        match mem::replace(&mut self.state, State::Disconnected) {
-            State::Connected(mut io) => {
-                let r = <AsyncWrite>::shutdown(&mut io);
-                self.state = State::Connected(io);
-                r
+        match self.state {
+            StreamState::Connected(ref mut io) => <AsyncWrite>::shutdown(io),
+            StreamState::Disconnected => Ok(Async::Ready(())),
+        }
+    }
+}
+
+/// When to reconnect a broken connection
+///
+/// The first attempt is made after `initial_delay`, and the delay is
+/// multiplied by `multiplier` after each failed attempt, up to `max_delay`.
+/// Once `max_attempts` attempts have failed, the server is taken out of the
+/// load balancer and left to health checking.
+///
+/// # Examples
+///
+/// ```
+/// use copra::channel::ReconnectPolicy;
+/// use std::time::Duration;
+///
+/// let policy = ReconnectPolicy::new()
+///     .initial_delay(Duration::from_millis(500))
+///     .multiplier(1.5)
+///     .max_delay(Duration::from_secs(5))
+///     .max_attempts(10);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    initial_delay: Duration,
+    multiplier: f64,
+    max_delay: Duration,
+    max_attempts: u32,
+}
+
+impl ReconnectPolicy {
+    /// Create a policy with the default settings.
+    ///
+    /// Reconnect after 200 milliseconds, doubling the delay up to 10 seconds,
+    /// and give up after 5 attempts.
+    pub fn new() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(200),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(10),
+            max_attempts: 5,
+        }
+    }
+
+    /// Set the delay before the first attempt.
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// Set how much the delay grows after each failed attempt.
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Set the longest delay between attempts.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Set how many attempts are made before giving up.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// The delay before the `attempt`th attempt, counted from 1.
+    fn delay(&self, attempt: u32) -> Duration {
+        let initial = secs_f64(self.initial_delay);
+        let max = secs_f64(self.max_delay);
+        let secs = (initial * self.multiplier.powi(attempt as i32 - 1)).min(max);
+        Duration::from_nanos((secs * 1e9).round() as u64)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy::new()
+    }
+}
+
+/// State of the connection to a server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Requests can be sent to the server
+    Connected,
+    /// The connection is broken, and the given attempt to reconnect is on
+    /// the way. Requests to the server fail at once.
+    Reconnecting(u32),
+    /// The connection is broken, and the server is taken out of the load
+    /// balancer until it passes a health check
+    Disconnected,
+}
+
+/// Changes of connection states, see `ConnectionWatch::changes`
+pub type ConnectionChanges = mpsc::UnboundedReceiver<(SocketAddr, ConnectionState)>;
+
+#[derive(Debug, Default)]
+struct WatchInner {
+    states: HashMap<SocketAddr, ConnectionState>,
+    subscribers: Vec<mpsc::UnboundedSender<(SocketAddr, ConnectionState)>>,
+}
+
+/// A handle to watch the connections of a channel
+///
+/// This tells a channel that cannot reach its servers from a slow one. It
+/// can be obtained from `Channel::connection_watch`, and cloned freely.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionWatch {
+    inner: Arc<Mutex<WatchInner>>,
+}
+
+impl ConnectionWatch {
+    pub(crate) fn new() -> Self {
+        ConnectionWatch::default()
+    }
+
+    /// The state of the connection to the server at `addr`, `None` if the
+    /// server is not known to the channel.
+    pub fn state(&self, addr: &SocketAddr) -> Option<ConnectionState> {
+        self.inner.lock().unwrap().states.get(addr).cloned()
+    }
+
+    /// The states of the connections to all the servers, sorted by address.
+    pub fn states(&self) -> Vec<(SocketAddr, ConnectionState)> {
+        let mut states: Vec<_> = self.inner
+            .lock()
+            .unwrap()
+            .states
+            .iter()
+            .map(|(&addr, &state)| (addr, state))
+            .collect();
+        states.sort_by_key(|&(addr, _)| addr);
+        states
+    }
+
+    /// Whether requests can be sent to any server.
+    pub fn is_connected(&self) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .states
+            .values()
+            .any(|&state| state == ConnectionState::Connected)
+    }
+
+    /// Get a stream of the state changes from now on.
+    pub fn changes(&self) -> ConnectionChanges {
+        let (sender, receiver) = mpsc::unbounded();
+        self.inner.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    pub(crate) fn set(&self, addr: SocketAddr, state: ConnectionState) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.states.insert(addr, state) == Some(state) {
+            return;
+        }
+        debug!("Connection to {} is {:?}", addr, state);
+        inner
+            .subscribers
+            .retain(|sender| sender.unbounded_send((addr, state)).is_ok());
+    }
+
+    pub(crate) fn remove(&self, addr: &SocketAddr) {
+        self.inner.lock().unwrap().states.remove(addr);
+    }
+}
+
+enum State {
+    Connected(Connection),
+    Reconnecting(u32),
+    Disconnected,
+}
+
+struct Inner {
+    addr: SocketAddr,
+    options: ConnectOptions,
+    state: RefCell<State>,
+    /// Bumped on every new connection, so that failures on a replaced
+    /// connection are ignored
+    generation: Cell<u64>,
+}
+
+/// A multiplexed connection to a server, which is reestablished following
+/// a `ReconnectPolicy` once it is broken
+///
+/// While the connection is down, requests fail at once, and the requests in
+/// flight when it broke fail as well. Without a policy the connection is
+/// never reestablished, and the server is left to health checking.
+pub(crate) struct Connector {
+    inner: Rc<Inner>,
+}
+
+impl Connector {
+    pub fn new(
+        addr: SocketAddr,
+        conn: Connection,
+        options: ConnectOptions,
+    ) -> Self {
+        options.watch.set(addr, ConnectionState::Connected);
+        Connector {
+            inner: Rc::new(Inner {
+                addr,
+                options,
+                state: RefCell::new(State::Connected(conn)),
+                generation: Cell::new(0),
+            }),
+        }
+    }
+
+    fn set_state(inner: &Inner, state: State) {
+        let watched = match state {
+            State::Connected(_) => ConnectionState::Connected,
+            State::Reconnecting(attempt) => ConnectionState::Reconnecting(attempt),
+            State::Disconnected => ConnectionState::Disconnected,
+        };
+        *inner.state.borrow_mut() = state;
+        inner.options.watch.set(inner.addr, watched);
+    }
+
+    /// Called when a request fails on the connection of `generation`.
+    fn lost(inner: &Rc<Inner>, generation: u64) {
+        let connected = match *inner.state.borrow() {
+            State::Connected(_) => true,
+            _ => false,
+        };
+        if !connected || inner.generation.get() != generation {
+            return;
+        }
+        warn!("Connection to {} is lost", inner.addr);
+        Connector::reconnect(inner, 1);
+    }
+
+    /// Schedule the `attempt`th attempt to reconnect, if the policy allows.
+    fn reconnect(inner: &Rc<Inner>, attempt: u32) {
+        let delay = match inner.options.reconnect {
+            Some(ref policy) if attempt <= policy.max_attempts => policy.delay(attempt),
+            _ => {
+                warn!("Stop reconnecting to {}", inner.addr);
+                return Connector::set_state(inner, State::Disconnected);
+            }
+        };
+        Connector::set_state(inner, State::Reconnecting(attempt));
+
+        let weak = Rc::downgrade(inner);
+        let (options, addr) = (inner.options.clone(), inner.addr);
+        let fut = inner
+            .options
+            .timer
+            .sleep(delay)
+            .then(move |_| connect_one(&options, addr))
+            .then(move |result| {
+                // the server has been removed
+                let inner = match Weak::upgrade(&weak) {
+                    Some(inner) => inner,
+                    None => return Ok(()),
+                };
+                match result {
+                    Ok(conn) => {
+                        info!("Reconnected to {}", addr);
+                        inner.generation.set(inner.generation.get() + 1);
+                        Connector::set_state(&inner, State::Connected(conn));
+                    }
+                    Err(e) => {
+                        debug!("Failed to reconnect to {}: {}", addr, e);
+                        Connector::reconnect(&inner, attempt + 1);
+                    }
+                }
+                Ok(())
+            });
+        inner.options.handle.spawn(fut);
+    }
+}
+
+impl Service for Connector {
+    type Request = RequestPackage;
+    type Response = ResponsePackage;
+    type Error = io::Error;
+    type Future = Box<Future<Item = ResponsePackage, Error = io::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let conn = match *self.inner.state.borrow() {
+            State::Connected(ref conn) => conn.clone(),
+            State::Reconnecting(attempt) => {
+                let e = io::Error::new(
+                    ErrorKind::NotConnected,
+                    format!(
+                        "connection to {} is down, reconnecting (attempt {})",
+                        self.inner.addr, attempt
+                    ),
+                );
+                return Box::new(future::err(e));
             }
-            State::Connecting(_) => {
-                self.state = State::Disconnected;
-                Ok(Async::Ready(()))
+            State::Disconnected => {
+                let e = io::Error::new(
+                    ErrorKind::NotConnected,
+                    format!("connection to {} is down", self.inner.addr),
+                );
+                return Box::new(future::err(e));
             }
-            State::Disconnected => Ok(Async::Ready(())),
-        }
+        };
+
+        let weak = Rc::downgrade(&self.inner);
+        let generation = self.inner.generation.get();
+        let addr = self.inner.addr;
+        let fut = cancel::call(&conn, req).map_err(move |e| {
+            if let Some(inner) = Weak::upgrade(&weak) {
+                Connector::lost(&inner, generation);
+            }
+            io::Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("connection to {} is lost: {}", addr, e),
+            )
+        });
+        Box::new(fut)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reconnect_delay_grows_up_to_max() {
+        let policy = ReconnectPolicy::new()
+            .initial_delay(Duration::from_millis(200))
+            .multiplier(1.5)
+            .max_delay(Duration::from_secs(1));
+        let delays: Vec<_> = (1..6).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_millis(200),
+                Duration::from_millis(300),
+                Duration::from_millis(450),
+                Duration::from_millis(675),
+                Duration::from_secs(1),
+            ]
+        );
     }
 }
@@ -0,0 +1,101 @@
+use bytes::Bytes;
+use futures::Future;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_service::Service;
+
+use load_balancer::ServerEndPort;
+use message::RpcRequestMeta;
+use naming::ServerNode;
+use stub::meta_to_error;
+
+use super::backend::Connect;
+
+/// Health of a server as seen by a channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerHealth {
+    /// The server is used by the load balancer
+    Healthy,
+    /// The server is removed from the load balancer, and is checked in the
+    /// background until it recovers
+    Unhealthy,
+}
+
+/// How unhealthy servers are checked
+pub(crate) struct HealthCheck {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub rpc: Option<(String, String)>,
+    pub listener: Option<Box<Fn(SocketAddr, ServerHealth)>>,
+}
+
+impl fmt::Debug for HealthCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HealthCheck")
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("rpc", &self.rpc)
+            .finish()
+    }
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            rpc: None,
+            listener: None,
+        }
+    }
+}
+
+impl HealthCheck {
+    /// Report the new health of a server.
+    pub fn notify(&self, addr: SocketAddr, health: ServerHealth) {
+        match health {
+            ServerHealth::Healthy => info!("Server {} recovered", addr),
+            ServerHealth::Unhealthy => warn!("Server {} is unhealthy", addr),
+        }
+        if let Some(ref listener) = self.listener {
+            listener(addr, health);
+        }
+    }
+
+    /// The delay before the next check.
+    pub fn next_backoff(&self, backoff: Duration) -> Duration {
+        (backoff * 2).min(self.max_backoff)
+    }
+
+    /// Connect to a server, and make sure it can serve the health check RPC
+    /// if there is one.
+    pub fn probe(
+        &self,
+        server: &ServerNode,
+        connect: &Connect,
+    ) -> Box<Future<Item = ServerEndPort, Error = io::Error>> {
+        let fut = connect(server);
+        let (service_name, method_name) = match self.rpc {
+            Some((ref service_name, ref method_name)) => (service_name.clone(), method_name.clone()),
+            None => return fut,
+        };
+
+        let fut = fut.and_then(move |end_port| {
+            let mut meta = RpcRequestMeta::new();
+            meta.set_service_name(service_name);
+            meta.set_method_name(method_name);
+            end_port
+                .call((meta, Bytes::new()))
+                .and_then(move |(meta, _)| match meta_to_error(&meta) {
+                    None => Ok(end_port),
+                    Some(e) => Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("health check rpc failed: {}", e),
+                    )),
+                })
+        });
+        Box::new(fut)
+    }
+}
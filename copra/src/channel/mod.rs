@@ -28,8 +28,7 @@ use tokio_core::reactor::Handle;
 use tokio_io::AsyncRead;
 use tokio_io::codec::Framed;
 use tokio_proto::multiplex::ClientProto;
-use tokio_proto::TcpClient;
-use futures::{Async, Future, IntoFuture, Poll};
+use futures::{future, stream, Async, Future, Poll, Stream};
 use futures::sync::mpsc;
 use futures::sync::oneshot;
 use std::error::Error;
@@ -38,18 +37,38 @@ use std::io;
 use std::net::{AddrParseError, SocketAddr};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio_timer::{Sleep, Timer};
 
 use protocol::{BrpcProtocol, ProtoCodecClient, Protocol, RpcProtocol};
-use load_balancer::{CallInfo, ServerEndPort, ServerId};
+use load_balancer::{self, CallInfo, LoadBalance, ServerEndPort, ServerId};
+use load_balancer::random::RandomLoadBalancer;
 use load_balancer::single_server::SingleServerLoadBalancer;
 use message::{RpcRequestMeta, RpcResponseMeta};
+use naming::{DnsNamingService, FileNamingService, NamingService, ServerListStream, ServerNode};
 
-use self::backend::ChannelBackend;
-use self::connector::Connector;
+use self::backend::{BackendConfig, ChannelBackend, Connect};
+use self::cancel::{CancelTransport, CancellableRequest};
+use self::connection::ConnectOptions;
+use self::connector::ClientStream;
+use self::health::HealthCheck;
+use self::rate_limit::TokenBucket;
+
+pub use self::circuit_breaker::CircuitBreaker;
+pub use self::connection::ConnectionType;
+pub use self::connector::{ConnectionChanges, ConnectionState, ConnectionWatch, ReconnectPolicy};
+pub use self::health::ServerHealth;
+pub use self::rate_limit::{RateLimit, Throttle};
+pub use self::retry::{DefaultRetryPolicy, RetryPolicy};
 
 mod backend;
+mod cancel;
+mod circuit_breaker;
+mod connection;
 pub(crate) mod connector;
+mod health;
+mod rate_limit;
+mod retry;
 
 /// A future returned by `ChannelBuilder::build` which will resolve to a `Channel`
 /// when the channel is ready for use.
@@ -65,13 +84,13 @@ type FeedbackSender = oneshot::Sender<(ServerId, CallInfo)>;
 
 type FeedbackReceiver = oneshot::Receiver<(ServerId, CallInfo)>;
 
-type OneShotSender = oneshot::Sender<io::Result<(ResponsePackage, FeedbackHandle)>>;
+type OneShotSender = oneshot::Sender<Result<(ResponsePackage, FeedbackHandle), ChannelError>>;
 
-type OneShotReceiver = oneshot::Receiver<io::Result<(ResponsePackage, FeedbackHandle)>>;
+type OneShotReceiver = oneshot::Receiver<Result<(ResponsePackage, FeedbackHandle), ChannelError>>;
 
-type ChannelSender = mpsc::UnboundedSender<(OneShotSender, RequestPackage)>;
+type ChannelSender = mpsc::UnboundedSender<(OneShotSender, RequestPackage, CallOptions)>;
 
-type ChannelReceiver = mpsc::UnboundedReceiver<(OneShotSender, RequestPackage)>;
+type ChannelReceiver = mpsc::UnboundedReceiver<(OneShotSender, RequestPackage, CallOptions)>;
 
 /// The error when building a channel
 #[derive(Clone, Debug)]
@@ -80,6 +99,14 @@ pub enum ChannelBuildError {
     AddrParseError(AddrParseError),
     /// Failed to connect to a server or a cluster
     ConnectError,
+    /// The server URL is malformed or its scheme is not supported
+    InvalidUrl(String),
+    /// The weight attached to a server is not a positive integer
+    InvalidWeight(String),
+    /// No load balancer is registered under this name
+    UnknownLoadBalancer(String),
+    /// The naming service failed to give the initial server list
+    NamingError(String),
 }
 
 impl fmt::Display for ChannelBuildError {
@@ -87,6 +114,14 @@ impl fmt::Display for ChannelBuildError {
         match *self {
             ChannelBuildError::AddrParseError(ref e) => write!(f, "address parse error: {}", e),
             ChannelBuildError::ConnectError => write!(f, "connection error"),
+            ChannelBuildError::InvalidUrl(ref url) => write!(f, "invalid server url: {}", url),
+            ChannelBuildError::InvalidWeight(ref server) => {
+                write!(f, "invalid server weight: {}", server)
+            }
+            ChannelBuildError::UnknownLoadBalancer(ref name) => {
+                write!(f, "unknown load balancer: {}", name)
+            }
+            ChannelBuildError::NamingError(ref e) => write!(f, "naming service error: {}", e),
         }
     }
 }
@@ -96,13 +131,17 @@ impl Error for ChannelBuildError {
         match *self {
             ChannelBuildError::AddrParseError(_) => "failed to parse socket address from raw string",
             ChannelBuildError::ConnectError => "failed to connect to a remote server",
+            ChannelBuildError::InvalidUrl(_) => "malformed or unsupported server url",
+            ChannelBuildError::InvalidWeight(_) => "server weight is not a positive integer",
+            ChannelBuildError::UnknownLoadBalancer(_) => "no load balancer has this name",
+            ChannelBuildError::NamingError(_) => "failed to get servers from the naming service",
         }
     }
 
     fn cause(&self) -> Option<&Error> {
         match *self {
             ChannelBuildError::AddrParseError(ref e) => Some(e),
-            ChannelBuildError::ConnectError => None,
+            _ => None,
         }
     }
 }
@@ -113,8 +152,16 @@ pub enum ChannelError {
     /// Can not issue new request because the number of pending requests has
     /// reached the concurrency limit
     ConcurrencyLimitReached,
+    /// The request is over the budget of the rate limit
+    RateLimited,
     /// Io error from TCP socket
     IoError(io::Error),
+    /// No response is received before the request deadline
+    Timeout,
+    /// The load balancer has no server to send the request to
+    NoServerAvailable,
+    /// The connection to the server is down, see `Channel::connection_watch`
+    Disconnected(io::Error),
     /// [WIP] Other errors that need to be explicated
     UnknownError,
 }
@@ -123,7 +170,11 @@ impl fmt::Display for ChannelError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ChannelError::ConcurrencyLimitReached => write!(f, "Concurrency limit reached"),
+            ChannelError::RateLimited => write!(f, "Rate limit exceeded"),
             ChannelError::IoError(ref e) => write!(f, "Io error: {}", e),
+            ChannelError::Timeout => write!(f, "Request timed out"),
+            ChannelError::NoServerAvailable => write!(f, "No server available"),
+            ChannelError::Disconnected(ref e) => write!(f, "Disconnected: {}", e),
             ChannelError::UnknownError => write!(f, "other errors might be worth discussion"),
         }
     }
@@ -133,14 +184,18 @@ impl Error for ChannelError {
     fn description(&self) -> &str {
         match *self {
             ChannelError::ConcurrencyLimitReached => "concurrency limit reached",
+            ChannelError::RateLimited => "rate limit exceeded",
             ChannelError::IoError(_) => "io error from TCP socket",
+            ChannelError::Timeout => "request timed out",
+            ChannelError::NoServerAvailable => "no server available",
+            ChannelError::Disconnected(_) => "connection to the server is down",
             ChannelError::UnknownError => "[WIP] other errors",
         }
     }
 
     fn cause(&self) -> Option<&Error> {
         match *self {
-            ChannelError::IoError(ref e) => Some(e),
+            ChannelError::IoError(ref e) | ChannelError::Disconnected(ref e) => Some(e),
             _ => None,
         }
     }
@@ -156,8 +211,8 @@ impl From<AddrParseError> for ChannelBuildError {
 #[doc(hidden)]
 pub struct MetaClientProtocol {
     proto: Box<RpcProtocol>,
-    handle: Handle,
     addr: SocketAddr,
+    notify_cancel: bool,
 }
 
 impl fmt::Debug for MetaClientProtocol {
@@ -168,7 +223,7 @@ impl fmt::Debug for MetaClientProtocol {
 
 impl MetaClientProtocol {
     /// Create a new instance.
-    pub fn new(proto_type: &Protocol, handle: Handle, addr: SocketAddr) -> Self {
+    pub fn new(proto_type: &Protocol, addr: SocketAddr) -> Self {
         let proto = match proto_type {
             // TODO: unify construction interface of protocols
             &Protocol::Brpc => Box::new(BrpcProtocol::new()),
@@ -176,29 +231,133 @@ impl MetaClientProtocol {
         };
         MetaClientProtocol {
             proto,
-            handle,
             addr,
+            notify_cancel: false,
         }
     }
+
+    /// Tell the server about the requests given up by the caller.
+    pub fn notify_cancel(mut self, notify: bool) -> Self {
+        self.notify_cancel = notify;
+        self
+    }
 }
 
 impl ClientProto<TcpStream> for MetaClientProtocol {
-    type Request = RequestPackage;
+    type Request = CancellableRequest;
     type Response = ResponsePackage;
-    type Transport = Framed<Connector, ProtoCodecClient>;
+    type Transport = CancelTransport<Framed<ClientStream, ProtoCodecClient>>;
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: TcpStream) -> Self::BindTransport {
-        let conn = Connector::from_stream(self.addr.clone(), io, self.handle.clone());
+        let conn = ClientStream::new(self.addr, io);
         let codec = ProtoCodecClient::new(self.proto.new_boxed());
         let framed = conn.framed(codec);
-        Ok(framed)
+        Ok(CancelTransport::new(framed, self.notify_cancel))
     }
 }
 
 #[derive(Debug)]
 enum ConnectMode<'a> {
     Single(&'a str),
+    List(Vec<&'a str>),
+    Url(&'a str),
+    Naming(Box<NamingService>),
+}
+
+/// Parse a server URL into the connect mode it stands for.
+///
+/// `list://` URLs contain comma separated server addresses, `file://` URLs
+/// contain the path of a server list file, and `dns://` URLs contain a host
+/// name and a port.
+fn parse_url<'a>(url: &'a str) -> Result<ConnectMode<'a>, ChannelBuildError> {
+    let invalid = || ChannelBuildError::InvalidUrl(url.to_string());
+    let idx = url.find("://").ok_or_else(&invalid)?;
+    let (scheme, rest) = (&url[..idx], &url[idx + 3..]);
+    match scheme {
+        "list" => {
+            let addrs: Vec<_> = rest.split(',').filter(|s| !s.trim().is_empty()).collect();
+            if addrs.is_empty() {
+                Err(invalid())
+            } else {
+                Ok(ConnectMode::List(addrs))
+            }
+        }
+        "file" if !rest.is_empty() => Ok(ConnectMode::Naming(Box::new(FileNamingService::new(
+            rest,
+        )))),
+        "dns" => {
+            let mut parts = rest.rsplitn(2, ':');
+            let port = parts.next().and_then(|port| port.parse::<u16>().ok());
+            let host = parts
+                .next()
+                .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+                .filter(|host| !host.is_empty());
+            match (host, port) {
+                (Some(host), Some(port)) => Ok(ConnectMode::Naming(Box::new(
+                    DnsNamingService::new(host, port),
+                ))),
+                _ => Err(invalid()),
+            }
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// A server list that never changes.
+fn static_list(addrs: &[&str]) -> Result<ServerListStream, ChannelBuildError> {
+    let servers = addrs
+        .iter()
+        .map(|addr| addr.parse::<ServerNode>())
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Box::new(stream::once(Ok(servers))))
+}
+
+/// Create a function that connects to servers.
+fn connector(options: ConnectOptions) -> Connect {
+    Box::new(move |server: &ServerNode| connection::connect(&options, server))
+}
+
+/// Servers connected, and the ones failed to connect
+type Connected = (Vec<(ServerNode, ServerEndPort)>, Vec<ServerNode>);
+
+/// Connect to all the servers.
+///
+/// Servers that fail to connect are returned separately. It is an error only
+/// if none of them can be connected.
+fn connect_all(
+    servers: Vec<ServerNode>,
+    connect: &Connect,
+) -> Box<Future<Item = Connected, Error = ChannelBuildError>> {
+    let connects: Vec<_> = servers
+        .into_iter()
+        .map(|server| {
+            connect(&server).then(move |result| match result {
+                Ok(end_port) => Ok(Ok((server, end_port))),
+                Err(e) => {
+                    warn!("Failed to connect to server {}: {}", server.addr, e);
+                    Ok(Err(server))
+                }
+            })
+        })
+        .collect();
+
+    let fut = future::join_all(connects).and_then(|results| {
+        let mut connected = Vec::new();
+        let mut failed = Vec::new();
+        for result in results {
+            match result {
+                Ok(server) => connected.push(server),
+                Err(server) => failed.push(server),
+            }
+        }
+        if connected.is_empty() {
+            Err(ChannelBuildError::ConnectError)
+        } else {
+            Ok((connected, failed))
+        }
+    });
+    Box::new(fut)
 }
 
 /// Channel factory, which can be used to setup a new channel
@@ -210,26 +369,87 @@ pub struct ChannelBuilder<'a> {
     mode: ConnectMode<'a>,
     handle: Handle,
     protocol: Option<Protocol>,
+    connection_type: Option<ConnectionType>,
+    reconnect_policy: Option<ReconnectPolicy>,
     deadline: Option<Option<Duration>>,
     max_retry: Option<u32>,
+    retry_policy: Option<Box<RetryPolicy>>,
+    backup_request: Option<Duration>,
+    health_check: HealthCheck,
+    circuit_breaker: Option<CircuitBreaker>,
     max_concurrency: Option<u32>,
+    rate_limit: Option<RateLimit>,
+    notify_cancel: bool,
+    load_balancer: Option<Box<LoadBalance>>,
+    load_balancer_name: Option<&'a str>,
 }
 
 impl<'a> ChannelBuilder<'a> {
-    /// Connect to a server by IP address.
-    ///
-    /// This method will create a new channel builder.
-    pub fn single_server(addr: &'a str, handle: Handle) -> Self {
+    fn new(mode: ConnectMode<'a>, handle: Handle) -> Self {
         ChannelBuilder {
-            mode: ConnectMode::Single(addr),
-            handle: handle,
+            mode,
+            handle,
             protocol: None,
+            connection_type: None,
+            reconnect_policy: None,
             deadline: None,
             max_retry: None,
+            retry_policy: None,
+            backup_request: None,
+            health_check: HealthCheck::default(),
+            circuit_breaker: None,
             max_concurrency: None,
+            rate_limit: None,
+            notify_cancel: false,
+            load_balancer: None,
+            load_balancer_name: None,
         }
     }
 
+    /// Connect to a server by IP address.
+    ///
+    /// This method will create a new channel builder.
+    pub fn single_server(addr: &'a str, handle: Handle) -> Self {
+        ChannelBuilder::new(ConnectMode::Single(addr), handle)
+    }
+
+    /// Connect to a list of servers by IP addresses.
+    ///
+    /// The channel connects to every server, and spreads requests among them
+    /// through the load balancer. Servers that fail to connect are left out,
+    /// the channel can be built as long as one of them is connected.
+    pub fn server_list(addrs: &[&'a str], handle: Handle) -> Self {
+        ChannelBuilder::new(ConnectMode::List(addrs.to_vec()), handle)
+    }
+
+    /// Connect to servers described by a URL.
+    ///
+    /// Supported schemes:
+    ///
+    /// * `list://127.0.0.1:8000,127.0.0.1:8001`: a static server list, the same
+    ///   as `server_list`.
+    /// * `file:///path/to/servers`: servers listed in a local file, which is
+    ///   watched for changes. See `FileNamingService`.
+    /// * `dns://example.com:8000`: all the addresses of a host name, which is
+    ///   looked up periodically. See `DnsNamingService`.
+    ///
+    /// A server address can be followed by a space and its weight, which is
+    /// used by weighted load balancers, e.g. `127.0.0.1:8000 2`.
+    pub fn from_url(url: &'a str, handle: Handle) -> Self {
+        ChannelBuilder::new(ConnectMode::Url(url), handle)
+    }
+
+    /// Connect to the servers given by a naming service.
+    ///
+    /// The channel is ready once the initial server list is connected. Later
+    /// changes are applied to the load balancer at runtime.
+    pub fn naming_service<N>(naming: N, handle: Handle) -> Self
+    where
+        N: NamingService + 'static,
+    {
+        ChannelBuilder::new(ConnectMode::Naming(Box::new(naming)), handle)
+    }
+
     /// [WIP] Choose a communication protocol.
     ///
     /// This RPC framework is intended to support multiple communication protocols
@@ -246,9 +466,43 @@ impl<'a> ChannelBuilder<'a> {
         self
     }
 
-    /// [WIP] Set request deadline.
+    /// Choose how connections to each server are made.
     ///
-    /// A request will be set to failed if it reaches its deadline.
+    /// A single multiplexed connection suits small requests. Large requests
+    /// and responses block the ones behind them on the same connection, in
+    /// which case a pool of connections, or a new connection for every
+    /// request, can help.
+    ///
+    /// Default to `ConnectionType::Single`.
+    pub fn connection_type(mut self, connection_type: ConnectionType) -> Self {
+        self.connection_type = Some(connection_type);
+        self
+    }
+
+    /// Reconnect broken connections following `policy`.
+    ///
+    /// While a connection is being reestablished, requests to the server fail
+    /// at once with `ChannelError::Disconnected`, and can be retried on other
+    /// servers. This applies to `ConnectionType::Single`, which is the
+    /// default.
+    ///
+    /// Default to `None`, a server with a broken connection is taken out of
+    /// the load balancer at once, and connected again once it passes a health
+    /// check.
+    pub fn reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = Some(policy);
+        self
+    }
+
+    /// Set request deadline.
+    ///
+    /// A request will be set to failed with `ChannelError::Timeout` if no response
+    /// is received before its deadline. The timeout is also reported to the load
+    /// balancer.
+    ///
+    /// The time left is sent along with the request, so that the server can
+    /// give up the request once the client stops waiting, see
+    /// `Controller::remaining`.
     ///
     /// Default to `None`. which means we will wait until the reponse is returned or
     /// some error is raised.
@@ -257,6 +511,113 @@ impl<'a> ChannelBuilder<'a> {
         self
     }
 
+    /// Set the maximum number of retries of a request.
+    ///
+    /// A failed request is sent again if the error is accepted by the retry
+    /// policy. Each retry asks the load balancer for a server again. The time
+    /// spent on retries also counts towards the request deadline.
+    ///
+    /// Default to 3.
+    pub fn max_retry(mut self, max_retry: u32) -> Self {
+        self.max_retry = Some(max_retry);
+        self
+    }
+
+    /// Set the policy that decides which errors can be retried.
+    ///
+    /// Default to `DefaultRetryPolicy`, which only retries connection errors.
+    pub fn retry_policy<P>(mut self, policy: P) -> Self
+    where
+        P: RetryPolicy + 'static,
+    {
+        self.retry_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Send a backup request if the response does not come back in `delay`.
+    ///
+    /// The backup request is sent to a server picked by the load balancer, and
+    /// the request resolves with whichever response comes back first. The other
+    /// one is cancelled, and its response is ignored if it arrives later. This
+    /// helps to cut down the tail latency of read-only requests.
+    ///
+    /// Default to `None`, no backup request is sent.
+    pub fn backup_request(mut self, delay: Duration) -> Self {
+        self.backup_request = Some(delay);
+        self
+    }
+
+    /// Set the delays between health checks of an unhealthy server.
+    ///
+    /// A server is taken out of the load balancer when its connection fails,
+    /// and is checked again after `initial`. The delay doubles after each
+    /// failed check, up to `max`. The server is put back once a check passes.
+    ///
+    /// Default to 500 milliseconds and 30 seconds.
+    pub fn health_check_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.health_check.initial_backoff = initial;
+        self.health_check.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Call a method in health checks.
+    ///
+    /// An unhealthy server passes the check only if it answers a request with
+    /// an empty body to `service_name.method_name` without error.
+    ///
+    /// Default to `None`, a server passes the check once it is connected.
+    pub fn health_check_rpc(mut self, service_name: &str, method_name: &str) -> Self {
+        self.health_check.rpc = Some((service_name.to_string(), method_name.to_string()));
+        self
+    }
+
+    /// Get notified when a server becomes unhealthy or recovers.
+    ///
+    /// `listener` is called on the event loop of the channel with the address
+    /// of the server and its new health.
+    pub fn health_listener<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(SocketAddr, ServerHealth) + 'static,
+    {
+        self.health_check.listener = Some(Box::new(listener));
+        self
+    }
+
+    /// Take servers that keep failing out of the load balancer for a while.
+    ///
+    /// Each server gets its own breaker, which works with every load
+    /// balancer. The health listener is notified when a breaker opens, and
+    /// when it is half-open.
+    ///
+    /// Default to `None`, no circuit breaker is used.
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// Set the load balancing algorithm.
+    ///
+    /// The connected servers will be added to `lb`.
+    ///
+    /// Default to `SingleServerLoadBalancer` for a single server, and
+    /// `RandomLoadBalancer` for multiple servers.
+    pub fn load_balancer<L>(mut self, lb: L) -> Self
+    where
+        L: LoadBalance + 'static,
+    {
+        self.load_balancer = Some(Box::new(lb));
+        self
+    }
+
+    /// Set the load balancing algorithm by name.
+    ///
+    /// See `load_balancer::by_name` for the available names. This is ignored
+    /// if a load balancer is set with `load_balancer`.
+    pub fn load_balancer_name(mut self, name: &'a str) -> Self {
+        self.load_balancer_name = Some(name);
+        self
+    }
+
     /// Set concurrency limit.
     ///
     /// The number of unresolved requests will be confined below `max_concurrency`.
@@ -269,6 +630,31 @@ impl<'a> ChannelBuilder<'a> {
         self
     }
 
+    /// Limit the number of requests sent per second.
+    ///
+    /// The budget is shared by all the clones of the channel. A delayed
+    /// request holds its place under the concurrency limit while it waits,
+    /// and its deadline starts once it is sent.
+    ///
+    /// Default to `None`, no limit imposed.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Tell the server when the caller drops the future of a request on the
+    /// way.
+    ///
+    /// The server can then see it with `Controller::is_cancelled`. Only
+    /// enable it for servers that understand the notice, others take it for
+    /// a new request, and the connection breaks on the extra response.
+    ///
+    /// Default to `false`, the dropped request is just not waited for.
+    pub fn notify_cancel(mut self, notify: bool) -> Self {
+        self.notify_cancel = notify;
+        self
+    }
+
     /// Consume the builder and begin to prepare connection.
     ///
     /// This method returns a future that will resolve to a `Channel`.
@@ -279,49 +665,260 @@ impl<'a> ChannelBuilder<'a> {
     pub fn build(self) -> ChannelBuildFuture {
         // TODO: use Default trait
         let protocol = self.protocol.unwrap_or(Protocol::Brpc);
-        // TODO: add timeout and retry
-        let _deadline = self.deadline.unwrap_or(None);
         let max_concurrency = self.max_concurrency.unwrap_or(1_000_000);
         let handle = self.handle;
+        let timer = Timer::default();
 
         let (tx, rx) = mpsc::unbounded();
-        let channel = Channel::new(tx, max_concurrency);
-
-        match self.mode {
-            ConnectMode::Single(addr) => {
-                let parse = addr.parse::<SocketAddr>()
-                    .map_err(|e| ChannelBuildError::AddrParseError(e))
-                    .into_future();
-                let fut = parse.and_then(move |addr| {
-                    let proto = MetaClientProtocol::new(&protocol, handle.clone(), addr.clone());
-                    TcpClient::new(proto)
-                        .connect(&addr, &handle)
-                        .map_err(|_| ChannelBuildError::ConnectError)
-                        .map(move |service| {
-                            let end_port = ServerEndPort::new(service);
-                            let lb = SingleServerLoadBalancer::new(end_port);
-                            let backend = ChannelBackend::new(rx, handle.clone(), lb);
-                            handle.spawn(backend);
-                            channel
-                        })
-                });
-                Box::new(fut)
+        let mut channel = Channel::new(tx, max_concurrency);
+        if let Some(limit) = self.rate_limit {
+            channel.rate_limit = Some((Arc::new(TokenBucket::new(limit)), rate_limit::timer()));
+        }
+
+        let connect = connector(ConnectOptions {
+            connection_type: self.connection_type.unwrap_or_default(),
+            protocol,
+            handle: handle.clone(),
+            timer: timer.clone(),
+            reconnect: self.reconnect_policy,
+            watch: channel.connection_watch(),
+            notify_cancel: self.notify_cancel,
+        });
+        let config = BackendConfig {
+            deadline: self.deadline.unwrap_or(None),
+            max_retry: self.max_retry.unwrap_or(3),
+            retry_policy: self.retry_policy
+                .unwrap_or_else(|| Box::new(DefaultRetryPolicy::new()) as Box<RetryPolicy>),
+            backup_request: self.backup_request,
+            health_check: self.health_check,
+            circuit_breaker: self.circuit_breaker,
+            connection_watch: channel.connection_watch(),
+        };
+
+        let mode = match self.mode {
+            ConnectMode::Url(url) => match parse_url(url) {
+                Ok(mode) => mode,
+                Err(e) => return Box::new(future::err(e)),
+            },
+            mode => mode,
+        };
+        let (single, updates) = match mode {
+            ConnectMode::Single(addr) => (true, static_list(&[addr])),
+            ConnectMode::List(addrs) => (false, static_list(&addrs)),
+            ConnectMode::Naming(naming) => (false, Ok(naming.watch(&handle))),
+            ConnectMode::Url(_) => unreachable!(),
+        };
+        let updates = match updates {
+            Ok(updates) => updates,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let lb = match (self.load_balancer, self.load_balancer_name) {
+            (Some(lb), _) => Some(lb),
+            (None, Some(name)) => match load_balancer::by_name(name) {
+                Some(lb) => Some(lb),
+                None => {
+                    let e = ChannelBuildError::UnknownLoadBalancer(name.to_string());
+                    return Box::new(future::err(e));
+                }
+            },
+            (None, None) => None,
+        };
+        let fut = updates
+            .into_future()
+            .map_err(|(e, _)| ChannelBuildError::NamingError(e.to_string()))
+            .and_then(|(servers, updates)| match servers {
+                Some(servers) => Ok((servers, updates)),
+                None => Err(ChannelBuildError::NamingError(
+                    "no server list is given".to_string(),
+                )),
+            })
+            .and_then(move |(servers, updates)| {
+                connect_all(servers, &connect).map(move |servers| (servers, updates, connect))
+            })
+            .map(move |((servers, failed), updates, connect)| {
+                // failed servers are numbered after the connected ones
+                let failed: Vec<_> = failed
+                    .into_iter()
+                    .enumerate()
+                    .map(|(idx, server)| (server, (servers.len() + idx) as ServerId))
+                    .collect();
+                let members = servers
+                    .iter()
+                    .enumerate()
+                    .map(|(id, (server, _))| (server.clone(), id as ServerId))
+                    .chain(failed.iter().cloned())
+                    .collect();
+                let mut end_ports = servers
+                    .into_iter()
+                    .enumerate()
+                    .map(|(id, (_, end_port))| (id as ServerId, end_port));
+                let lb = match lb {
+                    Some(mut lb) => {
+                        for (id, end_port) in end_ports {
+                            lb.add_server(id, end_port);
+                        }
+                        lb
+                    }
+                    None if single => {
+                        let (_, end_port) = end_ports.next().unwrap();
+                        Box::new(SingleServerLoadBalancer::new(end_port)) as Box<LoadBalance>
+                    }
+                    None => {
+                        let mut lb = RandomLoadBalancer::new();
+                        for (id, end_port) in end_ports {
+                            lb.add_server(id, end_port);
+                        }
+                        Box::new(lb) as Box<LoadBalance>
+                    }
+                };
+                let backend = ChannelBackend::new(rx, timer, config, lb)
+                    .watch(updates, connect, members)
+                    .check_health(failed);
+                handle.spawn(backend);
+                channel
+            });
+        Box::new(fut)
+    }
+}
+
+/// Options that apply to a single request
+///
+/// Every field left unset falls back to the configuration of the channel.
+///
+/// # Examples
+///
+/// ```
+/// use copra::channel::CallOptions;
+/// use std::time::Duration;
+///
+/// let options = CallOptions::new()
+///     .timeout(Duration::from_millis(500))
+///     .max_retry(2)
+///     .log_id(42);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CallOptions {
+    timeout: Option<Duration>,
+    max_retry: Option<u32>,
+    log_id: Option<i64>,
+    request_code: Option<u64>,
+}
+
+impl CallOptions {
+    /// Create a new instance with all options unset.
+    pub fn new() -> Self {
+        CallOptions::default()
+    }
+
+    /// Override the channel deadline for this request.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Override the maximum number of retries for this request.
+    pub fn max_retry(mut self, max_retry: u32) -> Self {
+        self.max_retry = Some(max_retry);
+        self
+    }
+
+    /// Set the log ID carried by the request meta, which helps to trace a
+    /// request across servers.
+    pub fn log_id(mut self, log_id: i64) -> Self {
+        self.log_id = Some(log_id);
+        self
+    }
+
+    /// Set the request code, which is used by load balancers such as
+    /// `ConsistentHashLoadBalancer` to send requests with the same code to the
+    /// same server.
+    pub fn request_code(mut self, request_code: u64) -> Self {
+        self.request_code = Some(request_code);
+        self
+    }
+
+    /// Get the timeout override.
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Get the retry override.
+    pub fn get_max_retry(&self) -> Option<u32> {
+        self.max_retry
+    }
+
+    /// Get the log ID.
+    pub fn get_log_id(&self) -> Option<i64> {
+        self.log_id
+    }
+
+    /// Get the request code.
+    pub fn get_request_code(&self) -> Option<u64> {
+        self.request_code
+    }
+}
+
+/// A request waiting for a token of the rate limit
+#[derive(Debug)]
+struct Delayed {
+    sleep: Sleep,
+    sender: ChannelSender,
+    request: Option<(RequestPackage, CallOptions)>,
+}
+
+#[derive(Debug)]
+enum CallState {
+    Delayed(Box<Delayed>),
+    Sent(OneShotReceiver),
+    Rejected(Option<ChannelError>),
+}
+
+/// A place under the concurrency limit of a channel, given back when dropped
+#[derive(Debug)]
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    /// Take a place if fewer than `max` requests are in flight.
+    fn acquire(counter: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        let mut current = counter.load(Ordering::SeqCst);
+        while current < max {
+            match counter.compare_exchange_weak(
+                current,
+                current + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return Some(InFlight(counter.clone())),
+                Err(actual) => current = actual,
             }
         }
+        None
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
 /// A future used internally by the framework. It will resolve to a serialized response.
+///
+/// The request holds its place under the concurrency limit until the response
+/// comes back, or the future is dropped.
 #[derive(Debug)]
 pub struct ChannelFuture {
-    rx: Option<OneShotReceiver>,
-    counter: Arc<AtomicUsize>,
+    state: CallState,
+    in_flight: Option<InFlight>,
 }
 
 impl ChannelFuture {
-    /// Create a new future, used internally
-    pub fn new(rx: Option<OneShotReceiver>, counter: Arc<AtomicUsize>) -> Self {
-        ChannelFuture { rx, counter }
+    fn with_state(state: CallState, in_flight: Option<InFlight>) -> Self {
+        ChannelFuture { state, in_flight }
+    }
+
+    fn rejected(e: ChannelError) -> Self {
+        ChannelFuture::with_state(CallState::Rejected(Some(e)), None)
     }
 }
 
@@ -331,18 +928,40 @@ impl Future for ChannelFuture {
     type Error = ChannelError;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Some(ref mut rx) = self.rx {
-            let result = try_ready!(
-                rx.poll()
-                    .map_err(|_| panic!("The sending end of the oneshot is dropped"))
-            );
-            self.counter.fetch_sub(1, Ordering::Relaxed);
-
-            result
-                .map_err(|e| ChannelError::IoError(e))
-                .map(|resp| Async::Ready(resp))
-        } else {
-            Err(ChannelError::ConcurrencyLimitReached)
+        loop {
+            let rx = match self.state {
+                CallState::Delayed(ref mut delayed) => {
+                    match delayed.sleep.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(())) => {}
+                        Err(e) => {
+                            warn!("Rate limit timer failed: {}", e);
+                            self.state = CallState::Rejected(None);
+                            return Err(ChannelError::RateLimited);
+                        }
+                    }
+                    let (req, options) = delayed.request.take().unwrap();
+                    let (tx, rx) = oneshot::channel();
+                    delayed
+                        .sender
+                        .unbounded_send((tx, req, options))
+                        .expect("The receiving end is dropped");
+                    rx
+                }
+                CallState::Sent(ref mut rx) => {
+                    let result = try_ready!(
+                        rx.poll()
+                            .map_err(|_| panic!("The sending end of the oneshot is dropped"))
+                    );
+                    self.in_flight.take();
+
+                    return result.map(Async::Ready);
+                }
+                CallState::Rejected(ref mut e) => {
+                    return Err(e.take().expect("The future is polled after an error"))
+                }
+            };
+            self.state = CallState::Sent(rx);
         }
     }
 }
@@ -356,6 +975,8 @@ pub struct Channel {
     sender: ChannelSender,
     counter: Arc<AtomicUsize>,
     max_concurrency: usize,
+    rate_limit: Option<(Arc<TokenBucket>, Timer)>,
+    watch: ConnectionWatch,
 }
 
 impl Channel {
@@ -367,27 +988,50 @@ impl Channel {
             sender,
             counter: Arc::new(AtomicUsize::new(0)),
             max_concurrency: max_concurrency as usize,
+            rate_limit: None,
+            watch: ConnectionWatch::new(),
         }
     }
 
+    /// Get a handle to watch the connections to the servers.
+    pub fn connection_watch(&self) -> ConnectionWatch {
+        self.watch.clone()
+    }
+
     /// Issue a request.
     ///
     /// This method deals with serialized, untyped message. It is meaned to be used
     /// internally by the framework. More ergonomic interfaces are provided by the 
     /// auto-generated stubs.
-    pub fn call(&self, req: RequestPackage) -> ChannelFuture {
-        let (tx, rx) = oneshot::channel();
-        let rx = if self.counter.load(Ordering::SeqCst) < self.max_concurrency {
-            self.counter.fetch_add(1, Ordering::SeqCst);
-            self.sender
-                .unbounded_send((tx, req))
-                .expect("The receiving end is dropped");
-            Some(rx)
-        } else {
-            None
+    pub fn call(&self, req: RequestPackage, options: CallOptions) -> ChannelFuture {
+        let in_flight = match InFlight::acquire(&self.counter, self.max_concurrency) {
+            Some(in_flight) => in_flight,
+            None => return ChannelFuture::rejected(ChannelError::ConcurrencyLimitReached),
+        };
+        let delay = match self.rate_limit {
+            Some((ref bucket, ref timer)) => match bucket.take(Instant::now()) {
+                Some(delay) if delay > Duration::from_secs(0) => Some(timer.sleep(delay)),
+                Some(_) => None,
+                None => return ChannelFuture::rejected(ChannelError::RateLimited),
+            },
+            None => None,
         };
 
-        ChannelFuture::new(rx, self.counter.clone())
+        let state = match delay {
+            Some(sleep) => CallState::Delayed(Box::new(Delayed {
+                sleep,
+                sender: self.sender.clone(),
+                request: Some((req, options)),
+            })),
+            None => {
+                let (tx, rx) = oneshot::channel();
+                self.sender
+                    .unbounded_send((tx, req, options))
+                    .expect("The receiving end is dropped");
+                CallState::Sent(rx)
+            }
+        };
+        ChannelFuture::with_state(state, Some(in_flight))
     }
 
     // TODO: deprecate this
@@ -400,16 +1044,24 @@ impl Channel {
 
 
 /// [WIP] Feedback handle to load balancers
+///
+/// If the handle is dropped without being called, the request is reported as
+/// succeeded.
 #[derive(Debug)]
 pub struct FeedbackHandle {
     id: ServerId,
-    sender: FeedbackSender,
+    start_usec: u64,
+    sender: Option<FeedbackSender>,
 }
 
 impl FeedbackHandle {
     /// Create a new handle.
-    pub fn new(id: ServerId, sender: FeedbackSender) -> Self {
-        FeedbackHandle { id, sender }
+    pub fn new(id: ServerId, start_usec: u64, sender: FeedbackSender) -> Self {
+        FeedbackHandle {
+            id,
+            start_usec,
+            sender: Some(sender),
+        }
     }
 
     /// Get server ID.
@@ -417,10 +1069,93 @@ impl FeedbackHandle {
         self.id
     }
 
+    /// Get the time the request was sent, see `CallInfo::start_usec`.
+    pub fn start_usec(&self) -> u64 {
+        self.start_usec
+    }
+
     /// Send feedback massage.
-    pub fn call(self, info: CallInfo) {
-        self.sender
-            .send((self.id, info))
-            .expect("The receiving end of the feedback channel is dropped");
+    pub fn call(mut self, info: CallInfo) {
+        self.send(info)
+    }
+
+    fn send(&mut self, info: CallInfo) {
+        if let Some(sender) = self.sender.take() {
+            // the channel backend might have shut down, nobody is interested
+            let _ = sender.send((self.id, info));
+        }
+    }
+}
+
+impl Drop for FeedbackHandle {
+    fn drop(&mut self) {
+        let info = CallInfo::new(self.start_usec, None);
+        self.send(info)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    fn request() -> RequestPackage {
+        (RpcRequestMeta::new(), Bytes::new())
+    }
+
+    #[test]
+    fn concurrency_limit_across_threads() {
+        let (tx, _rx) = mpsc::unbounded();
+        let channel = Channel::new(tx, 4);
+        // the requests admitted and not given back yet
+        let held = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let channel = channel.clone();
+                let held = held.clone();
+                thread::spawn(move || {
+                    let mut pending = Vec::new();
+                    for i in 0..20_000 {
+                        let fut = channel.call(request(), CallOptions::default());
+                        if fut.in_flight.is_some() {
+                            assert!(held.fetch_add(1, Ordering::SeqCst) < 4);
+                            pending.push(fut);
+                        }
+                        if i % 4 == 0 {
+                            held.fetch_sub(pending.len(), Ordering::SeqCst);
+                            pending.clear();
+                        }
+                    }
+                    held.fetch_sub(pending.len(), Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // the dropped futures gave their places back
+        assert_eq!(channel.counter.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn release_on_error() {
+        let (tx, rx) = mpsc::unbounded();
+        let channel = Channel::new(tx, 1);
+        let fut = channel.call(request(), CallOptions::default());
+        assert!(channel.congested());
+        let rejected = channel.call(request(), CallOptions::default());
+        match rejected.wait() {
+            Err(ChannelError::ConcurrencyLimitReached) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let (reply, _, _) = rx.wait().next().unwrap().unwrap();
+        reply.send(Err(ChannelError::Timeout)).unwrap();
+        match fut.wait() {
+            Err(ChannelError::Timeout) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(!channel.congested());
     }
 }
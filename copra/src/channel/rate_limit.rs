@@ -0,0 +1,213 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio_timer::{self, Timer};
+
+use time::secs_f64;
+
+/// Tick of the timer delaying requests
+const TICK_MS: u64 = 10;
+
+/// Slots of the timer, which can not sleep longer than `TICK_MS` times this
+const NUM_SLOTS: usize = 32_768;
+
+/// What to do with a request when the budget is used up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Throttle {
+    /// Fail the request at once with `ChannelError::RateLimited`
+    Reject,
+    /// Send the request once a token is free, or fail it with
+    /// `ChannelError::RateLimited` if it would wait longer than
+    /// `RateLimit::max_wait`
+    Delay,
+}
+
+/// Limit the number of requests sent per second
+///
+/// Requests take tokens from a bucket, which is refilled at `qps` tokens per
+/// second, and holds at most `burst` tokens. The bucket is shared by all the
+/// clones of a channel.
+///
+/// # Examples
+///
+/// ```
+/// use copra::channel::{RateLimit, Throttle};
+///
+/// use std::time::Duration;
+///
+/// let limit = RateLimit::new(100)
+///     .burst(10)
+///     .throttle(Throttle::Delay)
+///     .max_wait(Duration::from_millis(500));
+/// ```
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    qps: u32,
+    burst: Option<u32>,
+    throttle: Throttle,
+    max_wait: Duration,
+}
+
+impl RateLimit {
+    /// Send at most `qps` requests per second, the others are rejected.
+    pub fn new(qps: u32) -> Self {
+        RateLimit {
+            qps: qps.max(1),
+            burst: None,
+            throttle: Throttle::Reject,
+            max_wait: Duration::from_secs(1),
+        }
+    }
+
+    /// Set how many requests can be sent at once after an idle period.
+    ///
+    /// Default to `qps`.
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = Some(burst.max(1));
+        self
+    }
+
+    /// Choose what to do with the requests over the budget.
+    ///
+    /// Default to `Throttle::Reject`.
+    pub fn throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Set how long a request can wait for a token with `Throttle::Delay`.
+    ///
+    /// Default to 1 second. Waits longer than the timer can sleep, a little
+    /// over 5 minutes, are cut down to it.
+    pub fn max_wait(mut self, max_wait: Duration) -> Self {
+        let longest = Duration::from_millis(TICK_MS * (NUM_SLOTS as u64 - 1));
+        self.max_wait = max_wait.min(longest);
+        self
+    }
+}
+
+/// Create a timer to delay requests.
+///
+/// A sleep may end up to a tick early, the default tick of 100ms is too
+/// coarse for the rate limit.
+pub(crate) fn timer() -> Timer {
+    tokio_timer::wheel()
+        .tick_duration(Duration::from_millis(TICK_MS))
+        .num_slots(NUM_SLOTS)
+        .build()
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Below zero if tokens are promised to delayed requests
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// The token bucket shared by the clones of a channel
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    config: RateLimit,
+    bucket: Mutex<Bucket>,
+}
+
+impl TokenBucket {
+    pub fn new(config: RateLimit) -> Self {
+        let burst = config.burst.unwrap_or(config.qps);
+        TokenBucket {
+            config,
+            bucket: Mutex::new(Bucket {
+                tokens: f64::from(burst),
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take a token, and tell how long to wait before sending the request.
+    ///
+    /// `None` if the request is rejected.
+    pub fn take(&self, now: Instant) -> Option<Duration> {
+        let qps = f64::from(self.config.qps);
+        let burst = f64::from(self.config.burst.unwrap_or(self.config.qps));
+        let mut bucket = self.bucket.lock().unwrap();
+        if now > bucket.refilled {
+            let elapsed = secs_f64(now.duration_since(bucket.refilled));
+            bucket.tokens = (bucket.tokens + elapsed * qps).min(burst);
+            bucket.refilled = now;
+        }
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Some(Duration::from_secs(0));
+        }
+        match self.config.throttle {
+            Throttle::Reject => None,
+            Throttle::Delay => {
+                let wait = (1.0 - bucket.tokens) / qps;
+                if wait > secs_f64(self.config.max_wait) {
+                    return None;
+                }
+                // the token is promised to this request
+                bucket.tokens -= 1.0;
+                Some(Duration::from_micros((wait * 1e6).round() as u64))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reject_over_budget() {
+        let bucket = TokenBucket::new(RateLimit::new(10).burst(2));
+        let now = Instant::now();
+        assert!(bucket.take(now).is_some());
+        assert!(bucket.take(now).is_some());
+        assert_eq!(bucket.take(now), None);
+        // a token every 100ms
+        assert_eq!(bucket.take(now + Duration::from_millis(50)), None);
+        let later = now + Duration::from_millis(150);
+        assert_eq!(bucket.take(later), Some(Duration::from_secs(0)));
+        assert_eq!(bucket.take(later), None);
+        // no more than the burst after an idle period
+        let idle = later + Duration::from_secs(10);
+        for _ in 0..2 {
+            assert!(bucket.take(idle).is_some());
+        }
+        assert_eq!(bucket.take(idle), None);
+    }
+
+    #[test]
+    fn delay_over_budget() {
+        let bucket = TokenBucket::new(RateLimit::new(10).burst(1).throttle(Throttle::Delay));
+        let now = Instant::now();
+        assert_eq!(bucket.take(now), Some(Duration::from_secs(0)));
+        assert_eq!(bucket.take(now), Some(Duration::from_millis(100)));
+        assert_eq!(bucket.take(now), Some(Duration::from_millis(200)));
+        // the promised tokens are paid back first
+        let later = now + Duration::from_millis(100);
+        assert_eq!(bucket.take(later), Some(Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn reject_over_max_wait() {
+        let limit = RateLimit::new(10)
+            .burst(1)
+            .throttle(Throttle::Delay)
+            .max_wait(Duration::from_millis(300));
+        let bucket = TokenBucket::new(limit);
+        let now = Instant::now();
+        assert_eq!(bucket.take(now), Some(Duration::from_secs(0)));
+        for wait in 1..4 {
+            assert_eq!(bucket.take(now), Some(Duration::from_millis(100 * wait)));
+        }
+        // the queue does not grow past the longest wait
+        for _ in 0..100 {
+            assert_eq!(bucket.take(now), None);
+        }
+        let later = now + Duration::from_millis(100);
+        assert_eq!(bucket.take(later), Some(Duration::from_millis(300)));
+        assert_eq!(bucket.take(later), None);
+    }
+}
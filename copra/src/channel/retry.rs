@@ -0,0 +1,55 @@
+use std::fmt;
+
+use service::MethodError;
+
+use super::ChannelError;
+
+/// Decide which failed requests can be sent again
+///
+/// A retry is only issued when the request has not used up its retry budget
+/// (see `ChannelBuilder::max_retry` and `CallOptions::max_retry`). Every retry
+/// asks the load balancer for a server again, so it might land on another
+/// backend.
+pub trait RetryPolicy {
+    /// Return `true` if a request failed by `error` in the channel should be
+    /// retried.
+    fn retry_on_channel_error(&self, error: &ChannelError) -> bool;
+
+    /// Return `true` if a request marked as failed by the server should be
+    /// retried.
+    fn retry_on_method_error(&self, error: &MethodError) -> bool;
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RetryPolicy")
+    }
+}
+
+/// Retry requests that fail at the connection level
+///
+/// Io errors raised by the connection, and requests failed because the
+/// connection is down, are retried. Errors returned by the
+/// server are never retried, since the request might have been processed.
+#[derive(Clone, Debug, Default)]
+pub struct DefaultRetryPolicy;
+
+impl DefaultRetryPolicy {
+    /// Create a new instance.
+    pub fn new() -> Self {
+        DefaultRetryPolicy
+    }
+}
+
+impl RetryPolicy for DefaultRetryPolicy {
+    fn retry_on_channel_error(&self, error: &ChannelError) -> bool {
+        match *error {
+            ChannelError::IoError(_) | ChannelError::Disconnected(_) => true,
+            _ => false,
+        }
+    }
+
+    fn retry_on_method_error(&self, _: &MethodError) -> bool {
+        false
+    }
+}
@@ -1,6 +1,9 @@
 //! [WIP] Service controller
 
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use protocol::http::HttpStatus;
 
@@ -18,6 +21,29 @@ pub struct Controller {
     pub request_body: Vec<u8>,
     /// Response body in raw bytes
     pub response_body: Vec<u8>,
+    deadline: Option<Instant>,
+    log_id: Option<i64>,
+    cancelled: CancelFlag,
+}
+
+/// Set by the codec when the client gives up the request
+#[derive(Clone, Default, Debug)]
+pub(crate) struct CancelFlag(Arc<AtomicBool>);
+
+impl CancelFlag {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl PartialEq for CancelFlag {
+    fn eq(&self, other: &Self) -> bool {
+        self.is_set() == other.is_set()
+    }
 }
 
 impl Controller {
@@ -26,4 +52,50 @@ impl Controller {
         self.headers
             .insert("Content-Type".to_string(), s.to_string());
     }
+
+    /// Time left before the deadline of the request, `None` if the client
+    /// does not set one.
+    ///
+    /// The server gives up the request once it is zero.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| {
+            let now = Instant::now();
+            if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_secs(0)
+            }
+        })
+    }
+
+    /// Id given by the client to trace the request, `None` if the client
+    /// does not set one.
+    pub fn log_id(&self) -> Option<i64> {
+        self.log_id
+    }
+
+    /// Whether the deadline of the request has passed.
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::from_secs(0))
+    }
+
+    /// Whether the client has given up the request.
+    ///
+    /// The server keeps running the handler, long running ones can check this
+    /// to stop early. The response is discarded by the client anyway.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.is_set()
+    }
+
+    pub(crate) fn set_cancel_flag(&mut self, flag: CancelFlag) {
+        self.cancelled = flag;
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: Duration) {
+        self.deadline = Some(Instant::now() + timeout);
+    }
+
+    pub(crate) fn set_log_id(&mut self, log_id: i64) {
+        self.log_id = Some(log_id);
+    }
 }
@@ -11,6 +11,7 @@ use service::{EncapService, NewEncapService};
 /// their services to this struct.
 pub struct ServiceRegistry {
     registry: HashMap<String, HashMap<String, NewEncapService>>,
+    limits: HashMap<String, HashMap<String, usize>>,
 }
 
 impl fmt::Debug for ServiceRegistry {
@@ -30,6 +31,7 @@ impl ServiceRegistry {
     pub fn new() -> Self {
         ServiceRegistry {
             registry: HashMap::new(),
+            limits: HashMap::new(),
         }
     }
 
@@ -45,6 +47,38 @@ impl ServiceRegistry {
         self.registry.insert(<T as NamedRegistrant>::name().to_string(), map);
     }
 
+    /// Process at most `limit` requests to a method at the same time.
+    ///
+    /// The requests over the limit are rejected at once with
+    /// `ErrorCode::Overloaded`.
+    pub fn max_concurrency(&mut self, service_name: &str, method_name: &str, limit: usize) {
+        self.limits
+            .entry(service_name.to_string())
+            .or_default()
+            .insert(method_name.to_string(), limit);
+    }
+
+    pub(crate) fn concurrency_limits(&self) -> &HashMap<String, HashMap<String, usize>> {
+        &self.limits
+    }
+
+    /// Names of the registered methods, paired with their service names.
+    pub(crate) fn method_names(&self) -> Vec<(String, String)> {
+        self.registry
+            .iter()
+            .flat_map(|(service_name, methods)| {
+                methods
+                    .keys()
+                    .map(move |method_name| (service_name.clone(), method_name.clone()))
+            })
+            .collect()
+    }
+
+    /// Whether a service of the name is registered.
+    pub fn has_service(&self, service_name: &str) -> bool {
+        self.registry.contains_key(service_name)
+    }
+
     /// Get a method by service name and method name.
     /// 
     /// This method is used internally by generated stubs.
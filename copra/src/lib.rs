@@ -174,7 +174,9 @@ extern crate futures;
 extern crate httparse;
 #[macro_use]
 extern crate log;
+extern crate md5;
 extern crate protobuf;
+extern crate rand;
 extern crate smallvec;
 extern crate tokio_core;
 extern crate tokio_io;
@@ -183,14 +185,11 @@ extern crate tokio_service;
 extern crate tokio_timer;
 extern crate url;
 
-#[cfg(test)]
-extern crate rand;
-
-pub use channel::ChannelBuilder;
+pub use channel::{CallOptions, ChannelBuilder};
 pub use controller::Controller;
 pub use dispatcher::ServiceRegistry;
 pub use server::ServerBuilder;
-pub use service::MethodError;
+pub use service::{ErrorCode, MethodError};
 
 pub mod channel;
 pub mod controller;
@@ -198,8 +197,11 @@ pub mod codec;
 pub mod dispatcher;
 pub mod load_balancer;
 pub mod message;
+pub mod naming;
 pub mod protocol;
 pub mod service;
 pub mod stub;
 pub mod server;
 pub mod monitor;
+
+mod time;
@@ -0,0 +1,210 @@
+//! Map requests to servers by hashing
+
+use md5;
+use rand;
+use std::collections::HashMap;
+
+use super::{CallInfo, LoadBalance, SelectContext, ServerEndPort, ServerId};
+
+/// Number of virtual nodes each server owns on the hash ring
+const REPLICAS: u32 = 100;
+
+/// Hash function used to place servers on the ring
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashKind {
+    /// 32-bit murmur3, one virtual node per hash
+    Murmur3,
+    /// MD5 as in ketama, four virtual nodes per digest
+    Ketama,
+}
+
+/// Compute the 32-bit murmur3 hash of `key`.
+///
+/// This can be used to turn a request key into a request code.
+pub fn murmur3_32(key: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut hash = seed;
+    let (body, tail) = key.split_at(key.len() / 4 * 4);
+    for chunk in body.chunks(4) {
+        let mut k = u32::from(chunk[0]) | u32::from(chunk[1]) << 8 | u32::from(chunk[2]) << 16
+            | u32::from(chunk[3]) << 24;
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        hash ^= k;
+        hash = hash.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+
+    let mut k = 0u32;
+    for (i, &byte) in tail.iter().enumerate() {
+        k |= u32::from(byte) << (8 * i);
+    }
+    if !tail.is_empty() {
+        hash ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    hash ^= key.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 16)
+}
+
+/// Positions of the virtual nodes of the server at `addr`.
+fn virtual_nodes(kind: HashKind, addr: &str) -> Vec<u32> {
+    match kind {
+        HashKind::Murmur3 => (0..REPLICAS)
+            .map(|i| murmur3_32(format!("{}-{}", addr, i).as_bytes(), 0))
+            .collect(),
+        HashKind::Ketama => (0..REPLICAS / 4)
+            .flat_map(|i| {
+                let digest = md5::compute(format!("{}-{}", addr, i).as_bytes());
+                (0..4)
+                    .map(|j| {
+                        u32::from(digest[4 * j]) | u32::from(digest[4 * j + 1]) << 8
+                            | u32::from(digest[4 * j + 2]) << 16
+                            | u32::from(digest[4 * j + 3]) << 24
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect(),
+    }
+}
+
+/// Send requests with the same request code to the same server
+///
+/// Servers are placed on a hash ring by their addresses, so the mapping is
+/// the same across channels and only a small part of it changes when a
+/// server is added or removed. A request goes to the first server on the
+/// ring after its request code, with the high 32 bits folded into the low
+/// ones. Requests without a request code are spread randomly.
+#[derive(Debug)]
+pub struct ConsistentHashLoadBalancer {
+    kind: HashKind,
+    servers: HashMap<ServerId, ServerEndPort>,
+    ring: Vec<(u32, ServerId)>,
+}
+
+impl ConsistentHashLoadBalancer {
+    /// Create a new instance without any server.
+    pub fn new(kind: HashKind) -> Self {
+        ConsistentHashLoadBalancer {
+            kind,
+            servers: HashMap::new(),
+            ring: Vec::new(),
+        }
+    }
+
+    fn rebuild_ring(&mut self) {
+        let kind = self.kind;
+        let mut ring: Vec<_> = self.servers
+            .iter()
+            .flat_map(|(&id, server)| {
+                virtual_nodes(kind, &server.addr().to_string())
+                    .into_iter()
+                    .map(move |point| (point, id))
+            })
+            .collect();
+        ring.sort();
+        self.ring = ring;
+    }
+}
+
+impl LoadBalance for ConsistentHashLoadBalancer {
+    fn select_server(&mut self, ctx: &SelectContext) -> Option<(ServerId, &ServerEndPort)> {
+        if self.ring.is_empty() {
+            return None;
+        }
+        let code = ctx.request_code
+            .map(|code| (code ^ (code >> 32)) as u32)
+            .unwrap_or_else(rand::random);
+        let start = match self.ring.binary_search(&(code, 0)) {
+            Ok(idx) | Err(idx) => idx,
+        };
+
+        let id = self.ring[start % self.ring.len()].1;
+        Some((id, &self.servers[&id]))
+    }
+
+    fn feed_back(&mut self, _: ServerId, _: CallInfo) {}
+
+    fn add_server(&mut self, id: ServerId, server: ServerEndPort) {
+        self.servers.insert(id, server);
+        self.rebuild_ring();
+    }
+
+    fn remove_server(&mut self, id: ServerId) -> Option<ServerEndPort> {
+        let removed = self.servers.remove(&id);
+        if removed.is_some() {
+            self.rebuild_ring();
+        }
+        removed
+    }
+
+    fn name(&self) -> &'static str {
+        match self.kind {
+            HashKind::Murmur3 => "c_murmurhash",
+            HashKind::Ketama => "c_ketama",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use load_balancer::test::fake_server;
+
+    fn select(lb: &mut ConsistentHashLoadBalancer, code: u64) -> ServerId {
+        let ctx = SelectContext {
+            request_code: Some(code),
+        };
+        lb.select_server(&ctx).unwrap().0
+    }
+
+    #[test]
+    fn murmur3_known_values() {
+        assert_eq!(murmur3_32(b"", 0), 0);
+        assert_eq!(murmur3_32(b"", 1), 0x514e_28b7);
+        assert_eq!(murmur3_32(b"hello", 0), 0x248b_fa47);
+        assert_eq!(
+            murmur3_32(b"The quick brown fox jumps over the lazy dog", 0),
+            0x2e4f_f723
+        );
+    }
+
+    #[test]
+    fn virtual_nodes_are_stable() {
+        for &kind in &[HashKind::Murmur3, HashKind::Ketama] {
+            let nodes = virtual_nodes(kind, "127.0.0.1:8000");
+            assert_eq!(nodes.len(), REPLICAS as usize);
+            assert_eq!(nodes, virtual_nodes(kind, "127.0.0.1:8000"));
+            assert!(nodes != virtual_nodes(kind, "127.0.0.1:8001"));
+        }
+    }
+
+    #[test]
+    fn remove_only_remaps_its_keys() {
+        for &kind in &[HashKind::Murmur3, HashKind::Ketama] {
+            let mut lb = ConsistentHashLoadBalancer::new(kind);
+            for id in 0..4 {
+                lb.add_server(id, fake_server(8000 + id as u16));
+            }
+            let codes: Vec<u64> = (0..1000u64)
+                .map(|i| i.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+                .collect();
+            let before: Vec<_> = codes.iter().map(|&c| select(&mut lb, c)).collect();
+            assert!(before.contains(&1));
+
+            lb.remove_server(1).unwrap();
+            for (&code, &old) in codes.iter().zip(&before) {
+                let new = select(&mut lb, code);
+                if old == 1 {
+                    assert!(new != 1);
+                } else {
+                    assert_eq!(new, old);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Send more requests to servers that answer faster
+//!
+//! This is modeled on the locality-aware load balancing (LALB) of [brpc].
+//!
+//! [brpc]: https://github.com/brpc/brpc/blob/master/docs/en/lalb.md
+
+use rand::{self, Rng, XorShiftRng};
+
+use super::{CallInfo, LoadBalance, SelectContext, ServerEndPort, ServerId};
+
+/// Weight of a new latency sample in the moving average
+const SMOOTHING: f64 = 0.2;
+
+/// Failed requests count as this many times slower than they took
+const ERROR_PUNISHMENT: f64 = 2.0;
+
+/// Latency and load of a server
+#[derive(Clone, Debug, Default)]
+struct Stats {
+    /// Weighted moving average of latency in microseconds, `None` until the
+    /// first response
+    avg_latency: Option<f64>,
+    /// Requests sent but not reported yet
+    in_flight: u32,
+}
+
+impl Stats {
+    fn on_select(&mut self) {
+        self.in_flight += 1;
+    }
+
+    fn on_feed_back(&mut self, call_info: &CallInfo) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        // the request did not finish, so its latency is unknown
+        if call_info.cancelled {
+            return;
+        }
+
+        let mut latency = call_info.elapsed_usec() as f64;
+        if call_info.is_server_failure() {
+            latency = latency.max(self.avg_latency.unwrap_or(0.0)) * ERROR_PUNISHMENT;
+        }
+        self.avg_latency = Some(match self.avg_latency {
+            Some(avg) => avg * (1.0 - SMOOTHING) + latency * SMOOTHING,
+            None => latency,
+        });
+    }
+
+    /// Selection weight, roughly the throughput the server can take.
+    ///
+    /// `default_latency` is used when no response has come back yet.
+    fn weight(&self, default_latency: f64) -> f64 {
+        let latency = self.avg_latency.unwrap_or(default_latency);
+        1.0 / ((latency + 1.0) * f64::from(self.in_flight + 1))
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    id: ServerId,
+    server: ServerEndPort,
+    stats: Stats,
+}
+
+/// Pick servers randomly, weighted by their latency and in-flight requests
+///
+/// Each server keeps a weighted moving average of its latency, measured from
+/// the feedback. The chance of picking a server is inversely proportional to
+/// its average latency times its number of in-flight requests, so faster and
+/// less loaded servers get more traffic. Failed requests count as slow ones.
+/// Servers that have not answered yet are assumed to be as fast as the
+/// average.
+#[derive(Debug)]
+pub struct LocalityAwareLoadBalancer {
+    servers: Vec<Entry>,
+    rng: XorShiftRng,
+}
+
+impl LocalityAwareLoadBalancer {
+    /// Create a new instance without any server.
+    pub fn new() -> Self {
+        LocalityAwareLoadBalancer {
+            servers: Vec::new(),
+            rng: rand::weak_rng(),
+        }
+    }
+
+    fn pick(&mut self) -> Option<usize> {
+        if self.servers.is_empty() {
+            return None;
+        }
+
+        let known: Vec<_> = self.servers
+            .iter()
+            .filter_map(|entry| entry.stats.avg_latency)
+            .collect();
+        let default_latency = if known.is_empty() {
+            0.0
+        } else {
+            known.iter().sum::<f64>() / known.len() as f64
+        };
+
+        let weights: Vec<_> = self.servers
+            .iter()
+            .map(|entry| entry.stats.weight(default_latency))
+            .collect();
+        let mut point = self.rng.gen::<f64>() * weights.iter().sum::<f64>();
+        for (idx, weight) in weights.into_iter().enumerate() {
+            if point < weight {
+                return Some(idx);
+            }
+            point -= weight;
+        }
+        Some(self.servers.len() - 1)
+    }
+}
+
+impl Default for LocalityAwareLoadBalancer {
+    fn default() -> Self {
+        LocalityAwareLoadBalancer::new()
+    }
+}
+
+impl LoadBalance for LocalityAwareLoadBalancer {
+    fn select_server(&mut self, _: &SelectContext) -> Option<(ServerId, &ServerEndPort)> {
+        let idx = self.pick()?;
+        let entry = &mut self.servers[idx];
+        entry.stats.on_select();
+        Some((entry.id, &entry.server))
+    }
+
+    fn feed_back(&mut self, id: ServerId, call_info: CallInfo) {
+        if let Some(entry) = self.servers.iter_mut().find(|entry| entry.id == id) {
+            entry.stats.on_feed_back(&call_info);
+        }
+    }
+
+    fn add_server(&mut self, id: ServerId, server: ServerEndPort) {
+        self.remove_server(id);
+        self.servers.push(Entry {
+            id,
+            server,
+            stats: Stats::default(),
+        });
+    }
+
+    fn remove_server(&mut self, id: ServerId) -> Option<ServerEndPort> {
+        self.servers
+            .iter()
+            .position(|entry| entry.id == id)
+            .map(|idx| self.servers.swap_remove(idx).server)
+    }
+
+    fn name(&self) -> &'static str {
+        "la"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use load_balancer::now_usec;
+    use service::{ErrorCode, MethodError};
+
+    fn finished(latency_usec: u64, error: Option<MethodError>) -> CallInfo {
+        CallInfo::new(now_usec() - latency_usec, error)
+    }
+
+    #[test]
+    fn faster_server_weighs_more() {
+        let mut fast = Stats::default();
+        let mut slow = Stats::default();
+        for _ in 0..10 {
+            fast.on_select();
+            fast.on_feed_back(&finished(1_000, None));
+            slow.on_select();
+            slow.on_feed_back(&finished(10_000, None));
+        }
+        assert!(fast.weight(0.0) > 5.0 * slow.weight(0.0));
+    }
+
+    #[test]
+    fn in_flight_requests_reduce_weight() {
+        let mut stats = Stats::default();
+        stats.on_select();
+        stats.on_feed_back(&finished(1_000, None));
+        let idle = stats.weight(0.0);
+
+        stats.on_select();
+        stats.on_select();
+        assert!(stats.weight(0.0) < idle / 2.0);
+
+        // cancelled requests leave the latency alone
+        let avg = stats.avg_latency;
+        stats.on_feed_back(&CallInfo::cancelled(now_usec() - 1_000_000));
+        stats.on_feed_back(&CallInfo::cancelled(now_usec() - 1_000_000));
+        assert_eq!(stats.in_flight, 0);
+        assert_eq!(stats.avg_latency, avg);
+    }
+
+    #[test]
+    fn errors_count_as_slow() {
+        let mut ok = Stats::default();
+        let mut failed = Stats::default();
+        ok.on_select();
+        ok.on_feed_back(&finished(1_000, None));
+        failed.on_select();
+        failed.on_feed_back(&finished(1_000, Some(ErrorCode::Timeout.into())));
+        assert!(ok.weight(0.0) > failed.weight(0.0));
+    }
+}
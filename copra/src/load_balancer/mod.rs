@@ -1,63 +1,275 @@
 //! [WIP] Load balancer traits and algorithms
 
-use tokio_core::net::TcpStream;
-use tokio_proto::multiplex::ClientService;
+use futures::Future;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio_service::Service;
 
-use channel::MetaClientProtocol;
-use service::MethodError;
+use channel::{RequestPackage, ResponsePackage};
+use service::{ErrorCode, MethodError};
 
+pub mod consistent_hash;
+pub mod locality_aware;
+pub mod p2c;
+pub mod random;
+pub mod round_robin;
 pub mod single_server;
 
-type InnerService = ClientService<TcpStream, MetaClientProtocol>;
+type EndPortFuture = Box<Future<Item = ResponsePackage, Error = io::Error>>;
+
+type BoxedService = Box<
+    Service<
+        Request = RequestPackage,
+        Response = ResponsePackage,
+        Error = io::Error,
+        Future = EndPortFuture,
+    >,
+>;
 
 /// Server ID
 pub type ServerId = u64;
 
+/// Box the future returned by a service
+struct BoxedFuture<S>(S);
+
+impl<S> Service for BoxedFuture<S>
+where
+    S: Service<Request = RequestPackage, Response = ResponsePackage, Error = io::Error>,
+    S::Future: 'static,
+{
+    type Request = RequestPackage;
+    type Response = ResponsePackage;
+    type Error = io::Error;
+    type Future = EndPortFuture;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        Box::new(self.0.call(req))
+    }
+}
 
 /// Represent a load lalancing unit
-#[derive(Debug)]
-pub struct ServerEndPort(InnerService);
+pub struct ServerEndPort {
+    addr: SocketAddr,
+    service: BoxedService,
+    weight: u32,
+}
 
 impl ServerEndPort {
-    pub(crate) fn new(service: InnerService) -> Self {
-        ServerEndPort(service)
+    /// Create an end port that sends requests through `service`, which can be
+    /// a fake one in tests.
+    pub(crate) fn from_service<S>(addr: SocketAddr, service: S) -> Self
+    where
+        S: Service<Request = RequestPackage, Response = ResponsePackage, Error = io::Error>
+            + 'static,
+        S::Future: 'static,
+    {
+        ServerEndPort {
+            addr,
+            service: Box::new(BoxedFuture(service)),
+            weight: 1,
+        }
+    }
+
+    /// The address of the server.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub(crate) fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// The weight of the server, used by weighted algorithms.
+    ///
+    /// Default to 1.
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+}
+
+impl fmt::Debug for ServerEndPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerEndPort")
+            .field("addr", &self.addr)
+            .field("weight", &self.weight)
+            .finish()
     }
 }
 
 impl Service for ServerEndPort {
-    type Request = <InnerService as Service>::Request;
-    type Response = <InnerService as Service>::Response;
-    type Error = <InnerService as Service>::Error;
-    type Future = <InnerService as Service>::Future;
+    type Request = RequestPackage;
+    type Response = ResponsePackage;
+    type Error = io::Error;
+    type Future = EndPortFuture;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        self.0.call(req)
+        self.service.call(req)
     }
 }
 
+/// Information about the request to send, used to select a server
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SelectContext {
+    /// Request code attached by the caller, see `CallOptions::request_code`
+    pub request_code: Option<u64>,
+}
+
+/// Microseconds since the unix epoch, which is the clock of `CallInfo`.
+pub fn now_usec() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() * 1_000_000 + u64::from(now.subsec_micros())
+}
+
 /// Information needed by the load lalancer to adjust algorithm
+///
+/// Every request sent to a server is reported exactly once, including the
+/// ones cancelled before their responses come back.
 #[derive(Clone, Debug, Default)]
 pub struct CallInfo {
     /// If any error raised when processing a request
     pub error: Option<MethodError>,
-    /// When the request started
+    /// When the request was sent, in microseconds since the unix epoch
     pub start_usec: u64,
+    /// The request is cancelled, e.g. because a backup request was answered
+    /// first, so its response will never be seen
+    pub cancelled: bool,
 }
 
 impl CallInfo {
     /// Create a new instance.
     pub fn new(start_usec: u64, error: Option<MethodError>) -> Self {
-        CallInfo { start_usec, error }
+        CallInfo {
+            start_usec,
+            error,
+            cancelled: false,
+        }
+    }
+
+    /// Create a new instance for a cancelled request.
+    pub fn cancelled(start_usec: u64) -> Self {
+        CallInfo {
+            start_usec,
+            error: None,
+            cancelled: true,
+        }
     }
-}
 
+    /// Time elapsed since the request was sent.
+    pub fn elapsed_usec(&self) -> u64 {
+        now_usec().saturating_sub(self.start_usec)
+    }
+
+    /// Whether the request failed in a way that hints at an unhealthy server.
+    ///
+    /// Errors of the request itself, like failing to decode the response or
+    /// calling a missing method, do not count.
+    pub fn is_server_failure(&self) -> bool {
+        match self.error.as_ref().map(MethodError::code) {
+            Some(ErrorCode::Unknown)
+            | Some(ErrorCode::Timeout)
+            | Some(ErrorCode::ConnectionFailed)
+            | Some(ErrorCode::Overloaded) => true,
+            _ => false,
+        }
+    }
+}
 
 /// Something can serve as a load balancer
+///
+/// The channel takes a server out with `remove_server` while its connection
+/// is broken or its circuit breaker is open, and adds it back once it
+/// recovers. A load balancer may skip more servers by the feedback.
 pub trait LoadBalance {
     /// Select a server to send request.
-    fn select_server(&mut self) -> (ServerId, &ServerEndPort);
+    ///
+    /// Return `None` if there is no server to choose from.
+    fn select_server(&mut self, ctx: &SelectContext) -> Option<(ServerId, &ServerEndPort)>;
 
     /// Update load balancing state.
     fn feed_back(&mut self, id: ServerId, call_info: CallInfo);
+
+    /// Add a server to choose from.
+    ///
+    /// If a server with the same ID exists, it is replaced.
+    fn add_server(&mut self, id: ServerId, server: ServerEndPort);
+
+    /// Remove a server, and give it back if it exists.
+    fn remove_server(&mut self, id: ServerId) -> Option<ServerEndPort>;
+
+    /// Algorithm name.
+    fn name(&self) -> &'static str;
+}
+
+impl fmt::Debug for LoadBalance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Create a load balancer by its name.
+///
+/// Available names:
+///
+/// * `random`: `RandomLoadBalancer`
+/// * `rr`: `RoundRobinLoadBalancer`
+/// * `wrr`: `WeightedRoundRobinLoadBalancer`
+/// * `c_murmurhash`: `ConsistentHashLoadBalancer` with murmur3 hashing
+/// * `c_ketama`: `ConsistentHashLoadBalancer` with ketama hashing
+/// * `la`: `LocalityAwareLoadBalancer`
+/// * `p2c`: `P2cLoadBalancer`
+///
+/// Return `None` if the name is unknown.
+pub fn by_name(name: &str) -> Option<Box<LoadBalance>> {
+    match name {
+        "random" => Some(Box::new(random::RandomLoadBalancer::new())),
+        "rr" => Some(Box::new(round_robin::RoundRobinLoadBalancer::new())),
+        "wrr" => Some(Box::new(round_robin::WeightedRoundRobinLoadBalancer::new())),
+        "c_murmurhash" => Some(Box::new(consistent_hash::ConsistentHashLoadBalancer::new(
+            consistent_hash::HashKind::Murmur3,
+        ))),
+        "c_ketama" => Some(Box::new(consistent_hash::ConsistentHashLoadBalancer::new(
+            consistent_hash::HashKind::Ketama,
+        ))),
+        "la" => Some(Box::new(locality_aware::LocalityAwareLoadBalancer::new())),
+        "p2c" => Some(Box::new(p2c::P2cLoadBalancer::new())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use bytes::Bytes;
+    use futures::future::{self, FutureResult};
+    use std::io;
+    use tokio_service::Service;
+
+    use channel::{RequestPackage, ResponsePackage};
+    use message::RpcResponseMeta;
+
+    use super::ServerEndPort;
+
+    /// A service that answers every request with an empty response
+    pub struct FakeService;
+
+    impl Service for FakeService {
+        type Request = RequestPackage;
+        type Response = ResponsePackage;
+        type Error = io::Error;
+        type Future = FutureResult<ResponsePackage, io::Error>;
+
+        fn call(&self, _: Self::Request) -> Self::Future {
+            future::ok((RpcResponseMeta::new(), Bytes::new()))
+        }
+    }
+
+    /// Create an end port that does not connect to anything.
+    pub fn fake_server(port: u16) -> ServerEndPort {
+        let addr = format!("127.0.0.1:{}", port).parse().unwrap();
+        ServerEndPort::from_service(addr, FakeService)
+    }
 }
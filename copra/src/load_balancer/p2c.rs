@@ -0,0 +1,157 @@
+//! Pick the less loaded one of two random servers
+
+use rand::{self, Rng, XorShiftRng};
+
+use super::{CallInfo, LoadBalance, SelectContext, ServerEndPort, ServerId};
+
+#[derive(Debug)]
+struct Entry {
+    id: ServerId,
+    server: ServerEndPort,
+    in_flight: u32,
+}
+
+/// Power of two choices
+///
+/// Sample two servers at random, and send the request to the one with fewer
+/// in-flight requests. A request is in flight from the time it is sent until
+/// it is reported through the feedback. This needs no tuning, and keeps the
+/// load even when many clients share the same servers.
+#[derive(Debug)]
+pub struct P2cLoadBalancer {
+    servers: Vec<Entry>,
+    rng: XorShiftRng,
+}
+
+impl P2cLoadBalancer {
+    /// Create a new instance without any server.
+    pub fn new() -> Self {
+        P2cLoadBalancer {
+            servers: Vec::new(),
+            rng: rand::weak_rng(),
+        }
+    }
+
+    fn pick(&mut self) -> Option<usize> {
+        match self.servers.len() {
+            0 => None,
+            1 => Some(0),
+            len => {
+                let first = self.rng.gen_range(0, len);
+                // a different one from the rest
+                let second = (first + self.rng.gen_range(1, len)) % len;
+                if self.servers[second].in_flight < self.servers[first].in_flight {
+                    Some(second)
+                } else {
+                    Some(first)
+                }
+            }
+        }
+    }
+}
+
+impl Default for P2cLoadBalancer {
+    fn default() -> Self {
+        P2cLoadBalancer::new()
+    }
+}
+
+impl LoadBalance for P2cLoadBalancer {
+    fn select_server(&mut self, _: &SelectContext) -> Option<(ServerId, &ServerEndPort)> {
+        let idx = self.pick()?;
+        let entry = &mut self.servers[idx];
+        entry.in_flight += 1;
+        Some((entry.id, &entry.server))
+    }
+
+    fn feed_back(&mut self, id: ServerId, _: CallInfo) {
+        if let Some(entry) = self.servers.iter_mut().find(|entry| entry.id == id) {
+            entry.in_flight = entry.in_flight.saturating_sub(1);
+        }
+    }
+
+    fn add_server(&mut self, id: ServerId, server: ServerEndPort) {
+        self.remove_server(id);
+        self.servers.push(Entry {
+            id,
+            server,
+            in_flight: 0,
+        });
+    }
+
+    fn remove_server(&mut self, id: ServerId) -> Option<ServerEndPort> {
+        self.servers
+            .iter()
+            .position(|entry| entry.id == id)
+            .map(|idx| self.servers.swap_remove(idx).server)
+    }
+
+    fn name(&self) -> &'static str {
+        "p2c"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use load_balancer::test::fake_server;
+
+    fn balancer(servers: u64) -> P2cLoadBalancer {
+        let mut lb = P2cLoadBalancer::new();
+        for id in 0..servers {
+            lb.add_server(id, fake_server(8000 + id as u16));
+        }
+        lb
+    }
+
+    fn select(lb: &mut P2cLoadBalancer) -> ServerId {
+        lb.select_server(&SelectContext::default()).unwrap().0
+    }
+
+    fn in_flight(lb: &P2cLoadBalancer, id: ServerId) -> u32 {
+        lb.servers.iter().find(|entry| entry.id == id).unwrap().in_flight
+    }
+
+    #[test]
+    fn no_server() {
+        let mut lb = P2cLoadBalancer::new();
+        assert!(lb.select_server(&SelectContext::default()).is_none());
+    }
+
+    #[test]
+    fn pick_less_loaded_of_two() {
+        let mut lb = balancer(2);
+        for _ in 0..100 {
+            select(&mut lb);
+            let (a, b) = (in_flight(&lb, 0), in_flight(&lb, 1));
+            assert!(a.max(b) - a.min(b) <= 1);
+        }
+    }
+
+    #[test]
+    fn feedback_releases_in_flight() {
+        let mut lb = balancer(2);
+        for _ in 0..10 {
+            select(&mut lb);
+        }
+        for _ in 0..5 {
+            lb.feed_back(0, CallInfo::default());
+        }
+        assert_eq!(in_flight(&lb, 0), 0);
+        // server 0 is idle now, so it wins every time until it catches up
+        for _ in 0..5 {
+            assert_eq!(select(&mut lb), 0);
+        }
+    }
+
+    #[test]
+    fn remove_server() {
+        let mut lb = balancer(2);
+        let removed = lb.remove_server(0).unwrap();
+        assert_eq!(removed.addr(), "127.0.0.1:8000".parse().unwrap());
+        assert!(lb.remove_server(0).is_none());
+        for _ in 0..10 {
+            assert_eq!(select(&mut lb), 1);
+        }
+    }
+}
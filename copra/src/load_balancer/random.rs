@@ -0,0 +1,57 @@
+//! Pick a server randomly
+
+use rand::{self, Rng, XorShiftRng};
+
+use super::{CallInfo, LoadBalance, SelectContext, ServerId, ServerEndPort};
+
+/// Spread requests to servers uniformly at random
+#[derive(Debug)]
+pub struct RandomLoadBalancer {
+    servers: Vec<(ServerId, ServerEndPort)>,
+    rng: XorShiftRng,
+}
+
+impl RandomLoadBalancer {
+    /// Create a new instance without any server.
+    pub fn new() -> Self {
+        RandomLoadBalancer {
+            servers: Vec::new(),
+            rng: rand::weak_rng(),
+        }
+    }
+}
+
+impl Default for RandomLoadBalancer {
+    fn default() -> Self {
+        RandomLoadBalancer::new()
+    }
+}
+
+impl LoadBalance for RandomLoadBalancer {
+    fn select_server(&mut self, _: &SelectContext) -> Option<(ServerId, &ServerEndPort)> {
+        if self.servers.is_empty() {
+            return None;
+        }
+        let idx = self.rng.gen_range(0, self.servers.len());
+        let (id, ref server) = self.servers[idx];
+        Some((id, server))
+    }
+
+    fn feed_back(&mut self, _: ServerId, _: CallInfo) {}
+
+    fn add_server(&mut self, id: ServerId, server: ServerEndPort) {
+        self.remove_server(id);
+        self.servers.push((id, server));
+    }
+
+    fn remove_server(&mut self, id: ServerId) -> Option<ServerEndPort> {
+        self.servers
+            .iter()
+            .position(|&(current, _)| current == id)
+            .map(|idx| self.servers.swap_remove(idx).1)
+    }
+
+    fn name(&self) -> &'static str {
+        "random"
+    }
+}
@@ -0,0 +1,288 @@
+//! Pick servers in turn
+
+use std::time::{Duration, Instant};
+
+use super::{CallInfo, LoadBalance, SelectContext, ServerEndPort, ServerId};
+
+/// Consecutive failures before a server is skipped
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// Milliseconds a failing server is skipped before it is tried again
+const UNHEALTHY_SKIP_MS: u64 = 1000;
+
+#[derive(Debug)]
+struct Entry {
+    id: ServerId,
+    server: ServerEndPort,
+    current_weight: i64,
+    failures: u32,
+    unhealthy_since: Option<Instant>,
+}
+
+impl Entry {
+    fn new(id: ServerId, server: ServerEndPort) -> Self {
+        Entry {
+            id,
+            server,
+            current_weight: 0,
+            failures: 0,
+            unhealthy_since: None,
+        }
+    }
+
+    /// Whether requests can be sent to the server at `now`.
+    fn is_available(&self, now: Instant) -> bool {
+        match self.unhealthy_since {
+            Some(since) => now.duration_since(since) >= Duration::from_millis(UNHEALTHY_SKIP_MS),
+            None => true,
+        }
+    }
+}
+
+fn feed_back(servers: &mut [Entry], id: ServerId, call_info: &CallInfo) {
+    let entry = match servers.iter_mut().find(|entry| entry.id == id) {
+        Some(entry) => entry,
+        None => return,
+    };
+    if call_info.cancelled {
+        return;
+    }
+    if call_info.is_server_failure() {
+        entry.failures += 1;
+        if entry.failures >= MAX_CONSECUTIVE_FAILURES {
+            entry.unhealthy_since = Some(Instant::now());
+        }
+    } else {
+        entry.failures = 0;
+        entry.unhealthy_since = None;
+    }
+}
+
+fn remove_server(servers: &mut Vec<Entry>, id: ServerId) -> Option<ServerEndPort> {
+    servers
+        .iter()
+        .position(|entry| entry.id == id)
+        .map(|idx| servers.remove(idx).server)
+}
+
+/// Send requests to each server in turn
+///
+/// A server is skipped after a few consecutive failures reported by the
+/// feedback, until it answers a request again or a second has passed. If all
+/// of them are skipped, they are used in turn anyway.
+#[derive(Debug, Default)]
+pub struct RoundRobinLoadBalancer {
+    servers: Vec<Entry>,
+    next: usize,
+}
+
+impl RoundRobinLoadBalancer {
+    /// Create a new instance without any server.
+    pub fn new() -> Self {
+        RoundRobinLoadBalancer::default()
+    }
+}
+
+impl LoadBalance for RoundRobinLoadBalancer {
+    fn select_server(&mut self, _: &SelectContext) -> Option<(ServerId, &ServerEndPort)> {
+        let len = self.servers.len();
+        if len == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let idx = (0..len)
+            .map(|offset| (self.next + offset) % len)
+            .find(|&idx| self.servers[idx].is_available(now))
+            .unwrap_or(self.next % len);
+        self.next = idx + 1;
+
+        let entry = &self.servers[idx];
+        Some((entry.id, &entry.server))
+    }
+
+    fn feed_back(&mut self, id: ServerId, call_info: CallInfo) {
+        feed_back(&mut self.servers, id, &call_info)
+    }
+
+    fn add_server(&mut self, id: ServerId, server: ServerEndPort) {
+        self.remove_server(id);
+        self.servers.push(Entry::new(id, server));
+    }
+
+    fn remove_server(&mut self, id: ServerId) -> Option<ServerEndPort> {
+        remove_server(&mut self.servers, id)
+    }
+
+    fn name(&self) -> &'static str {
+        "rr"
+    }
+}
+
+/// Send requests to each server in turn, in proportion to their weights
+///
+/// This is the smooth weighted round robin used by nginx, which interleaves
+/// the servers instead of sending a burst of requests to the heaviest one.
+/// Failing servers are skipped as by `RoundRobinLoadBalancer`.
+#[derive(Debug, Default)]
+pub struct WeightedRoundRobinLoadBalancer {
+    servers: Vec<Entry>,
+}
+
+impl WeightedRoundRobinLoadBalancer {
+    /// Create a new instance without any server.
+    pub fn new() -> Self {
+        WeightedRoundRobinLoadBalancer::default()
+    }
+
+    fn pick(&mut self, now: Instant, skip_unhealthy: bool) -> Option<usize> {
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for idx in 0..self.servers.len() {
+            let entry = &mut self.servers[idx];
+            if skip_unhealthy && !entry.is_available(now) {
+                continue;
+            }
+            let weight = i64::from(entry.server.weight());
+            entry.current_weight += weight;
+            total += weight;
+            let current_weight = entry.current_weight;
+            match best {
+                Some(best) if self.servers[best].current_weight >= current_weight => {}
+                _ => best = Some(idx),
+            }
+        }
+        if let Some(best) = best {
+            self.servers[best].current_weight -= total;
+        }
+        best
+    }
+}
+
+impl LoadBalance for WeightedRoundRobinLoadBalancer {
+    fn select_server(&mut self, _: &SelectContext) -> Option<(ServerId, &ServerEndPort)> {
+        let now = Instant::now();
+        let idx = match self.pick(now, true) {
+            Some(idx) => idx,
+            None => self.pick(now, false)?,
+        };
+
+        let entry = &self.servers[idx];
+        Some((entry.id, &entry.server))
+    }
+
+    fn feed_back(&mut self, id: ServerId, call_info: CallInfo) {
+        feed_back(&mut self.servers, id, &call_info)
+    }
+
+    fn add_server(&mut self, id: ServerId, server: ServerEndPort) {
+        self.remove_server(id);
+        self.servers.push(Entry::new(id, server));
+    }
+
+    fn remove_server(&mut self, id: ServerId) -> Option<ServerEndPort> {
+        remove_server(&mut self.servers, id)
+    }
+
+    fn name(&self) -> &'static str {
+        "wrr"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use load_balancer::test::fake_server;
+    use service::ErrorCode;
+
+    fn select<L: LoadBalance>(lb: &mut L) -> ServerId {
+        lb.select_server(&SelectContext::default()).unwrap().0
+    }
+
+    fn fail<L: LoadBalance>(lb: &mut L, id: ServerId) {
+        lb.feed_back(id, CallInfo::new(0, Some(ErrorCode::Overloaded.into())));
+    }
+
+    #[test]
+    fn rr_in_turn() {
+        let mut lb = RoundRobinLoadBalancer::new();
+        assert!(lb.select_server(&SelectContext::default()).is_none());
+        for id in 0..3 {
+            lb.add_server(id, fake_server(8000 + id as u16));
+        }
+        let picked: Vec<_> = (0..6).map(|_| select(&mut lb)).collect();
+        assert_eq!(picked, vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn rr_remove_and_add() {
+        let mut lb = RoundRobinLoadBalancer::new();
+        for id in 0..3 {
+            lb.add_server(id, fake_server(8000 + id as u16));
+        }
+        assert_eq!(select(&mut lb), 0);
+        let removed = lb.remove_server(1).unwrap();
+        assert_eq!(removed.addr(), "127.0.0.1:8001".parse().unwrap());
+        assert!(lb.remove_server(1).is_none());
+        let picked: Vec<_> = (0..4).map(|_| select(&mut lb)).collect();
+        assert_eq!(picked, vec![2, 0, 2, 0]);
+
+        lb.add_server(3, fake_server(8003));
+        let picked: Vec<_> = (0..3).map(|_| select(&mut lb)).collect();
+        assert_eq!(picked, vec![2, 3, 0]);
+    }
+
+    #[test]
+    fn rr_skip_failing() {
+        let mut lb = RoundRobinLoadBalancer::new();
+        for id in 0..3 {
+            lb.add_server(id, fake_server(8000 + id as u16));
+        }
+        for _ in 0..MAX_CONSECUTIVE_FAILURES {
+            fail(&mut lb, 1);
+        }
+        let picked: Vec<_> = (0..4).map(|_| select(&mut lb)).collect();
+        assert_eq!(picked, vec![0, 2, 0, 2]);
+
+        // a success puts it back
+        lb.feed_back(1, CallInfo::default());
+        let picked: Vec<_> = (0..3).map(|_| select(&mut lb)).collect();
+        assert_eq!(picked, vec![0, 1, 2]);
+
+        // used anyway if all of them fail
+        for id in 0..3 {
+            for _ in 0..MAX_CONSECUTIVE_FAILURES {
+                fail(&mut lb, id);
+            }
+        }
+        let picked: Vec<_> = (0..3).map(|_| select(&mut lb)).collect();
+        assert_eq!(picked, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn wrr_smooth_sequence() {
+        let mut lb = WeightedRoundRobinLoadBalancer::new();
+        for (id, &weight) in [5, 1, 1].iter().enumerate() {
+            let server = fake_server(8000 + id as u16).with_weight(weight);
+            lb.add_server(id as ServerId, server);
+        }
+        for _ in 0..2 {
+            let picked: Vec<_> = (0..7).map(|_| select(&mut lb)).collect();
+            assert_eq!(picked, vec![0, 0, 1, 0, 2, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn wrr_skip_failing() {
+        let mut lb = WeightedRoundRobinLoadBalancer::new();
+        for (id, &weight) in [5, 1, 1].iter().enumerate() {
+            let server = fake_server(8000 + id as u16).with_weight(weight);
+            lb.add_server(id as ServerId, server);
+        }
+        for _ in 0..MAX_CONSECUTIVE_FAILURES {
+            fail(&mut lb, 0);
+        }
+        for _ in 0..10 {
+            assert!(select(&mut lb) != 0);
+        }
+    }
+}
@@ -1,26 +1,48 @@
 //! Load balancing for a single server
 
-use super::{CallInfo, LoadBalance, ServerId, ServerEndPort};
+use super::{CallInfo, LoadBalance, SelectContext, ServerId, ServerEndPort};
 
 /// Provide load balancing for a single server
 #[derive(Debug)]
 pub struct SingleServerLoadBalancer {
-    service: ServerEndPort,
+    server: Option<(ServerId, ServerEndPort)>,
 }
 
 impl SingleServerLoadBalancer {
     /// Create a new instance.
     pub fn new(service: ServerEndPort) -> Self {
         SingleServerLoadBalancer {
-            service: service,
+            server: Some((0, service)),
         }
     }
 }
 
 impl LoadBalance for SingleServerLoadBalancer {
-    fn select_server(&mut self) -> (ServerId, &ServerEndPort) {
-        (0, &self.service)
+    fn select_server(&mut self, _: &SelectContext) -> Option<(ServerId, &ServerEndPort)> {
+        self.server.as_ref().map(|&(id, ref server)| (id, server))
     }
 
     fn feed_back(&mut self, _: ServerId, _: CallInfo) {}
+
+    fn add_server(&mut self, id: ServerId, server: ServerEndPort) {
+        self.server = Some((id, server));
+    }
+
+    fn remove_server(&mut self, id: ServerId) -> Option<ServerEndPort> {
+        match self.server.take() {
+            Some((current, server)) => {
+                if current == id {
+                    Some(server)
+                } else {
+                    self.server = Some((current, server));
+                    None
+                }
+            }
+            None => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "single_server"
+    }
 }
@@ -27,6 +27,7 @@ pub struct RpcMeta {
     pub request: ::protobuf::SingularPtrField<RpcRequestMeta>,
     pub response: ::protobuf::SingularPtrField<RpcResponseMeta>,
     pub correlation_id: u64,
+    pub cancel: bool,
     // special fields
     unknown_fields: ::protobuf::UnknownFields,
     cached_size: ::protobuf::CachedSize,
@@ -154,6 +155,29 @@ impl RpcMeta {
     fn mut_correlation_id_for_reflect(&mut self) -> &mut u64 {
         &mut self.correlation_id
     }
+
+    // bool cancel = 100;
+
+    pub fn clear_cancel(&mut self) {
+        self.cancel = false;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_cancel(&mut self, v: bool) {
+        self.cancel = v;
+    }
+
+    pub fn get_cancel(&self) -> bool {
+        self.cancel
+    }
+
+    fn get_cancel_for_reflect(&self) -> &bool {
+        &self.cancel
+    }
+
+    fn mut_cancel_for_reflect(&mut self) -> &mut bool {
+        &mut self.cancel
+    }
 }
 
 impl ::protobuf::Message for RpcMeta {
@@ -188,6 +212,13 @@ impl ::protobuf::Message for RpcMeta {
                     let tmp = is.read_uint64()?;
                     self.correlation_id = tmp;
                 },
+                100 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_bool()?;
+                    self.cancel = tmp;
+                },
                 _ => {
                     ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
                 },
@@ -211,6 +242,9 @@ impl ::protobuf::Message for RpcMeta {
         if self.correlation_id != 0 {
             my_size += ::protobuf::rt::value_size(4, self.correlation_id, ::protobuf::wire_format::WireTypeVarint);
         }
+        if self.cancel != false {
+            my_size += 3;
+        }
         my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
         self.cached_size.set(my_size);
         my_size
@@ -230,6 +264,9 @@ impl ::protobuf::Message for RpcMeta {
         if self.correlation_id != 0 {
             os.write_uint64(4, self.correlation_id)?;
         }
+        if self.cancel != false {
+            os.write_bool(100, self.cancel)?;
+        }
         os.write_unknown_fields(self.get_unknown_fields())?;
         ::std::result::Result::Ok(())
     }
@@ -289,6 +326,11 @@ impl ::protobuf::MessageStatic for RpcMeta {
                     RpcMeta::get_correlation_id_for_reflect,
                     RpcMeta::mut_correlation_id_for_reflect,
                 ));
+                fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeBool>(
+                    "cancel",
+                    RpcMeta::get_cancel_for_reflect,
+                    RpcMeta::mut_cancel_for_reflect,
+                ));
                 ::protobuf::reflect::MessageDescriptor::new::<RpcMeta>(
                     "RpcMeta",
                     fields,
@@ -304,6 +346,7 @@ impl ::protobuf::Clear for RpcMeta {
         self.clear_request();
         self.clear_response();
         self.clear_correlation_id();
+        self.clear_cancel();
         self.unknown_fields.clear();
     }
 }
@@ -326,6 +369,7 @@ pub struct RpcRequestMeta {
     pub service_name: ::std::string::String,
     pub method_name: ::std::string::String,
     pub log_id: i64,
+    pub timeout_ms: i32,
     // special fields
     unknown_fields: ::protobuf::UnknownFields,
     cached_size: ::protobuf::CachedSize,
@@ -439,6 +483,29 @@ impl RpcRequestMeta {
     fn mut_log_id_for_reflect(&mut self) -> &mut i64 {
         &mut self.log_id
     }
+
+    // int32 timeout_ms = 8;
+
+    pub fn clear_timeout_ms(&mut self) {
+        self.timeout_ms = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_timeout_ms(&mut self, v: i32) {
+        self.timeout_ms = v;
+    }
+
+    pub fn get_timeout_ms(&self) -> i32 {
+        self.timeout_ms
+    }
+
+    fn get_timeout_ms_for_reflect(&self) -> &i32 {
+        &self.timeout_ms
+    }
+
+    fn mut_timeout_ms_for_reflect(&mut self) -> &mut i32 {
+        &mut self.timeout_ms
+    }
 }
 
 impl ::protobuf::Message for RpcRequestMeta {
@@ -463,6 +530,13 @@ impl ::protobuf::Message for RpcRequestMeta {
                     let tmp = is.read_int64()?;
                     self.log_id = tmp;
                 },
+                8 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_int32()?;
+                    self.timeout_ms = tmp;
+                },
                 _ => {
                     ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
                 },
@@ -484,6 +558,9 @@ impl ::protobuf::Message for RpcRequestMeta {
         if self.log_id != 0 {
             my_size += ::protobuf::rt::value_size(3, self.log_id, ::protobuf::wire_format::WireTypeVarint);
         }
+        if self.timeout_ms != 0 {
+            my_size += ::protobuf::rt::value_size(8, self.timeout_ms, ::protobuf::wire_format::WireTypeVarint);
+        }
         my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
         self.cached_size.set(my_size);
         my_size
@@ -499,6 +576,9 @@ impl ::protobuf::Message for RpcRequestMeta {
         if self.log_id != 0 {
             os.write_int64(3, self.log_id)?;
         }
+        if self.timeout_ms != 0 {
+            os.write_int32(8, self.timeout_ms)?;
+        }
         os.write_unknown_fields(self.get_unknown_fields())?;
         ::std::result::Result::Ok(())
     }
@@ -558,6 +638,11 @@ impl ::protobuf::MessageStatic for RpcRequestMeta {
                     RpcRequestMeta::get_log_id_for_reflect,
                     RpcRequestMeta::mut_log_id_for_reflect,
                 ));
+                fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeInt32>(
+                    "timeout_ms",
+                    RpcRequestMeta::get_timeout_ms_for_reflect,
+                    RpcRequestMeta::mut_timeout_ms_for_reflect,
+                ));
                 ::protobuf::reflect::MessageDescriptor::new::<RpcRequestMeta>(
                     "RpcRequestMeta",
                     fields,
@@ -573,6 +658,7 @@ impl ::protobuf::Clear for RpcRequestMeta {
         self.clear_service_name();
         self.clear_method_name();
         self.clear_log_id();
+        self.clear_timeout_ms();
         self.unknown_fields.clear();
     }
 }
@@ -809,15 +895,16 @@ impl ::protobuf::reflect::ProtobufValue for RpcResponseMeta {
 }
 
 static file_descriptor_proto_data: &'static [u8] = b"\
-    \n\x1ccopra/src/message/meta.proto\"\x89\x01\n\x07RpcMeta\x12)\n\x07requ\
+    \n\x1ccopra/src/message/meta.proto\"\xa1\x01\n\x07RpcMeta\x12)\n\x07requ\
     est\x18\x01\x20\x01(\x0b2\x0f.RpcRequestMetaR\x07request\x12,\n\x08respo\
     nse\x18\x02\x20\x01(\x0b2\x10.RpcResponseMetaR\x08response\x12%\n\x0ecor\
-    relation_id\x18\x04\x20\x01(\x04R\rcorrelationId\"k\n\x0eRpcRequestMeta\
-    \x12!\n\x0cservice_name\x18\x01\x20\x01(\tR\x0bserviceName\x12\x1f\n\x0b\
-    method_name\x18\x02\x20\x01(\tR\nmethodName\x12\x15\n\x06log_id\x18\x03\
-    \x20\x01(\x03R\x05logId\"O\n\x0fRpcResponseMeta\x12\x1d\n\nerror_code\
-    \x18\x01\x20\x01(\x05R\terrorCode\x12\x1d\n\nerror_text\x18\x02\x20\x01(\
-    \tR\terrorTextb\x06proto3\
+    relation_id\x18\x04\x20\x01(\x04R\rcorrelationId\x12\x16\n\x06cancel\x18\
+    d\x20\x01(\x08R\x06cancel\"\x8a\x01\n\x0eRpcRequestMeta\x12!\n\x0cservic\
+    e_name\x18\x01\x20\x01(\tR\x0bserviceName\x12\x1f\n\x0bmethod_name\x18\
+    \x02\x20\x01(\tR\nmethodName\x12\x15\n\x06log_id\x18\x03\x20\x01(\x03R\
+    \x05logId\x12\x1d\n\ntimeout_ms\x18\x08\x20\x01(\x05R\ttimeoutMs\"O\n\
+    \x0fRpcResponseMeta\x12\x1d\n\nerror_code\x18\x01\x20\x01(\x05R\terrorCo\
+    de\x12\x1d\n\nerror_text\x18\x02\x20\x01(\tR\terrorTextb\x06proto3\
 ";
 
 static mut file_descriptor_proto_lazy: ::protobuf::lazy::Lazy<::protobuf::descriptor::FileDescriptorProto> = ::protobuf::lazy::Lazy {
@@ -117,3 +117,49 @@ impl Stream for ThroughputMaintainer {
         Ok(Async::Ready(Some(())))
     }
 }
+
+/// Counters of requests that are not dispatched to a handler
+///
+/// A sudden growth of missing services or methods usually means that clients
+/// are calling with a wrong name, or that the server is not the version they
+/// expect.
+#[derive(Debug, Default)]
+pub struct DispatchFailures {
+    no_such_service: AtomicUsize,
+    no_such_method: AtomicUsize,
+    overloaded: AtomicUsize,
+}
+
+impl DispatchFailures {
+    /// Create zeroed counters.
+    pub fn new() -> Self {
+        DispatchFailures::default()
+    }
+
+    /// Number of requests to services that are not registered.
+    pub fn no_such_service(&self) -> usize {
+        self.no_such_service.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests to methods that the service does not provide.
+    pub fn no_such_method(&self) -> usize {
+        self.no_such_method.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests rejected for exceeding a concurrency limit.
+    pub fn overloaded(&self) -> usize {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_no_such_service(&self) {
+        self.no_such_service.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_no_such_method(&self) {
+        self.no_such_method.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_overloaded(&self) {
+        self.overloaded.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,95 @@
+use futures::Future;
+use futures::sync::oneshot;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio_core::reactor::Handle;
+
+use super::{poll_periodically, NamingService, ServerListStream, ServerNode};
+
+/// Look up the addresses of a host
+///
+/// Lookups are run in a background thread, so they can block.
+pub trait Resolve: Send + Sync {
+    /// Return all the addresses of `host`, with `port` attached.
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+impl fmt::Debug for Resolve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Resolve")
+    }
+}
+
+/// Look up hosts with the resolver of the operating system
+#[derive(Clone, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+/// Use every address of a host name as a server
+///
+/// Both IPv4 and IPv6 addresses are used. The host is looked up again
+/// periodically, and a new list is yielded when the addresses change. If a
+/// lookup fails, the channel keeps using the previous addresses.
+#[derive(Clone, Debug)]
+pub struct DnsNamingService {
+    host: String,
+    port: u16,
+    interval: Duration,
+    resolver: Arc<Resolve>,
+}
+
+impl DnsNamingService {
+    /// Create a new instance, which connects to `port` of all the addresses
+    /// of `host`.
+    pub fn new<S: Into<String>>(host: S, port: u16) -> Self {
+        DnsNamingService {
+            host: host.into(),
+            port,
+            interval: Duration::from_secs(5),
+            resolver: Arc::new(SystemResolver),
+        }
+    }
+
+    /// Set how often the host is looked up.
+    ///
+    /// Default to 5 seconds. Intervals shorter than 200 milliseconds are
+    /// raised to it.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set the resolver used to look up the host.
+    ///
+    /// Default to `SystemResolver`.
+    pub fn resolver<R: Resolve + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+}
+
+impl NamingService for DnsNamingService {
+    fn watch(&self, handle: &Handle) -> ServerListStream {
+        let (host, port) = (self.host.clone(), self.port);
+        let resolver = self.resolver.clone();
+        poll_periodically(self.interval, handle, move || {
+            let (host, resolver) = (host.clone(), resolver.clone());
+            let (tx, rx) = oneshot::channel();
+            thread::spawn(move || {
+                let _ = tx.send(resolver.resolve(&host, port));
+            });
+            rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "resolver thread panicked"))
+                .and_then(|result| result)
+                .map(|addrs| addrs.into_iter().map(ServerNode::new).collect())
+        })
+    }
+}
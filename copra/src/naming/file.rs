@@ -0,0 +1,64 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio_core::reactor::Handle;
+
+use super::{poll_periodically, NamingService, ServerListStream, ServerNode};
+
+/// Read servers from a local file
+///
+/// The file contains a server on each line, in the form of `addr [weight]`,
+/// e.g. `127.0.0.1:8000 2`. Empty lines and lines starting with `#` are
+/// ignored. The file is checked periodically, and a new list is yielded when
+/// its content changes.
+#[derive(Clone, Debug)]
+pub struct FileNamingService {
+    path: PathBuf,
+    interval: Duration,
+}
+
+impl FileNamingService {
+    /// Create a new instance, which reads servers from `path`.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileNamingService {
+            path: path.into(),
+            interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Set how often the file is checked.
+    ///
+    /// Default to 1 second. Intervals shorter than 200 milliseconds are
+    /// raised to it.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+fn read_server_list(path: &Path) -> io::Result<Vec<ServerNode>> {
+    let mut content = String::new();
+    File::open(path)?.read_to_string(&mut content)?;
+
+    let servers = content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match line.parse::<ServerNode>() {
+            Ok(server) => Some(server),
+            Err(e) => {
+                warn!("Ignore invalid server {:?} in {}: {}", line, path.display(), e);
+                None
+            }
+        })
+        .collect();
+    Ok(servers)
+}
+
+impl NamingService for FileNamingService {
+    fn watch(&self, handle: &Handle) -> ServerListStream {
+        let path = self.path.clone();
+        poll_periodically(self.interval, handle, move || read_server_list(&path))
+    }
+}
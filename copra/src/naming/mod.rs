@@ -0,0 +1,117 @@
+//! Naming services, which tell a channel where the servers are
+//!
+//! A naming service yields the list of servers every time it changes. The
+//! channel connects to the new servers and adds them to the load balancer,
+//! and removes the servers that are gone. Requests already sent to a server
+//! that stays in the list are not affected.
+
+use futures::{stream, IntoFuture, Stream};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Interval};
+
+use channel::ChannelBuildError;
+
+pub use self::dns::{DnsNamingService, Resolve, SystemResolver};
+pub use self::file::FileNamingService;
+
+mod dns;
+mod file;
+
+/// Shortest interval between two fetches, so as not to busy poll
+const MIN_INTERVAL_MS: u64 = 200;
+
+/// A stream of server lists, each one replaces the previous one
+pub type ServerListStream = Box<Stream<Item = Vec<ServerNode>, Error = io::Error>>;
+
+/// A server known by a naming service
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ServerNode {
+    /// Address of the server
+    pub addr: SocketAddr,
+    /// Weight of the server, used by weighted load balancers
+    pub weight: u32,
+}
+
+impl ServerNode {
+    /// Create a new instance with weight 1.
+    pub fn new(addr: SocketAddr) -> Self {
+        ServerNode { addr, weight: 1 }
+    }
+}
+
+impl FromStr for ServerNode {
+    type Err = ChannelBuildError;
+
+    /// Parse a server address, optionally followed by its weight.
+    ///
+    /// For example, `127.0.0.1:8000 3` has a weight of 3. The weight defaults
+    /// to 1.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let addr = parts.next().unwrap_or("").parse::<SocketAddr>()?;
+        let weight = match (parts.next(), parts.next()) {
+            (None, _) => 1,
+            (Some(weight), None) => match weight.parse::<u32>() {
+                Ok(weight) if weight > 0 => weight,
+                _ => return Err(ChannelBuildError::InvalidWeight(s.to_string())),
+            },
+            _ => return Err(ChannelBuildError::InvalidWeight(s.to_string())),
+        };
+        Ok(ServerNode { addr, weight })
+    }
+}
+
+/// Something that knows where the servers are
+pub trait NamingService {
+    /// Start watching the server list.
+    ///
+    /// The returned stream should yield the current list first, then a new
+    /// list whenever it changes. Errors are logged by the channel, which keeps
+    /// using the last list and continues to poll the stream.
+    fn watch(&self, handle: &Handle) -> ServerListStream;
+}
+
+impl fmt::Debug for NamingService {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NamingService")
+    }
+}
+
+/// Fetch the server list now and after every `interval`, and yield it when
+/// it changes.
+///
+/// The ticks come from the event loop of `handle`.
+fn poll_periodically<F, R>(interval: Duration, handle: &Handle, mut fetch: F) -> ServerListStream
+where
+    F: FnMut() -> R + 'static,
+    R: IntoFuture<Item = Vec<ServerNode>, Error = io::Error> + 'static,
+    R::Future: 'static,
+{
+    let interval = interval.max(Duration::from_millis(MIN_INTERVAL_MS));
+    let ticks = match Interval::new(interval, handle) {
+        Ok(ticks) => ticks,
+        Err(e) => return Box::new(stream::once(Err(e))),
+    };
+    let mut last = None;
+    let lists = stream::once(Ok(()))
+        .chain(ticks)
+        .and_then(move |_| fetch())
+        .map(|mut servers| {
+            servers.sort();
+            servers.dedup();
+            servers
+        })
+        .filter(move |servers| {
+            if last.as_ref() == Some(servers) {
+                false
+            } else {
+                last = Some(servers.clone());
+                true
+            }
+        });
+    Box::new(lists)
+}
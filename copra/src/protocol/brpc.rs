@@ -206,4 +206,17 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn meta_fields_numbered_as_brpc() {
+        // int32 timeout_ms = 8 in baidu_std
+        let mut request_meta = RpcRequestMeta::new();
+        request_meta.set_timeout_ms(100);
+        assert_eq!(convert_to_bytes(request_meta), Bytes::from(&[0x40, 100][..]));
+
+        // bool cancel = 100, clear of the fields used by brpc
+        let mut meta = RpcMeta::new();
+        meta.set_cancel(true);
+        assert_eq!(convert_to_bytes(meta), Bytes::from(&[0xa0, 0x06, 1][..]));
+    }
 }
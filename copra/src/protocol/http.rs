@@ -20,6 +20,10 @@ pub enum HttpStatus {
     Ok,
     /// 403 Forbidden
     Forbidden,
+    /// 404 Not Found
+    NotFound,
+    /// 503 Service Unavailable
+    ServiceUnavailable,
 }
 
 impl HttpStatus {
@@ -28,6 +32,8 @@ impl HttpStatus {
         match *self {
             HttpStatus::Ok => 200,
             HttpStatus::Forbidden => 403,
+            HttpStatus::NotFound => 404,
+            HttpStatus::ServiceUnavailable => 503,
         }
     }
 
@@ -36,6 +42,8 @@ impl HttpStatus {
         match *self {
             HttpStatus::Ok => "200 OK",
             HttpStatus::Forbidden => "403 Forbidden",
+            HttpStatus::NotFound => "404 Not Found",
+            HttpStatus::ServiceUnavailable => "503 Service Unavailable",
         }
     }
 }
@@ -224,13 +232,16 @@ impl RpcProtocol for HttpProtocol {
                 buf.put_slice(&controller.response_body);
             }
             _ => {
-                let response_len = status_line.as_bytes().len();
+                // an error response has no body
+                let empty_headers = "Content-Length: 0\r\n\r\n";
+                let response_len = status_line.as_bytes().len() + empty_headers.len();
                 let free_len = buf.remaining_mut();
                 if free_len < response_len {
                     buf.reserve(response_len);
                 }
 
                 buf.put_slice(status_line.as_bytes());
+                buf.put_slice(empty_headers.as_bytes());
             }
         }
         Ok(())
@@ -2,12 +2,14 @@
 
 use bytes::{Bytes, BytesMut};
 use smallvec::SmallVec;
+use std::collections::HashMap;
 use std::fmt;
 use std::io;
+use std::time::Duration;
 use tokio_io::codec::{Decoder, Encoder};
 use tokio_proto::multiplex::RequestId;
 
-use controller::Controller;
+use controller::{CancelFlag, Controller};
 use message::{RpcMeta, RpcRequestMeta, RpcResponseMeta};
 use message::{RequestPackage, ResponsePackage};
 
@@ -75,11 +77,16 @@ impl fmt::Debug for RpcProtocol {
 /// it succeeds in decoding the request. Since `copra` use keep-alive connections
 /// to exchange messages, this match is cached so that the protocol resolution
 /// overhead is only incurred when receiving the first request.
+///
+/// Cancel notices from the client are not passed on, they set the flag read
+/// by `Controller::is_cancelled` instead.
 #[derive(Debug)]
 pub struct ProtoCodec {
     schemes: SmallVec<[Box<RpcProtocol>; 4]>,
     cached_scheme: usize,
     tried_num: i32,
+    /// Requests not answered yet
+    cancel_flags: HashMap<RequestId, CancelFlag>,
 }
 
 // impl fmt::Debug for ProtoCodec {
@@ -106,6 +113,7 @@ impl ProtoCodec {
             schemes,
             cached_scheme: 0,
             tried_num: 0,
+            cancel_flags: HashMap::new(),
         }
     }
 }
@@ -117,8 +125,15 @@ impl Decoder for ProtoCodec {
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         loop {
             match self.schemes[self.cached_scheme].try_parse(buf) {
-                Ok((id, (mut meta, controller, body))) => {
+                Ok((id, (mut meta, mut controller, body))) => {
                     self.tried_num = 0;
+                    if meta.get_cancel() {
+                        debug!("Request {} is cancelled by the client", id);
+                        if let Some(flag) = self.cancel_flags.get(&id) {
+                            flag.cancel();
+                        }
+                        continue;
+                    }
                     // if !meta.has_request() {
                     //     warn!("Request package do not have request field");
                     //     return Err(io::Error::new(
@@ -126,7 +141,19 @@ impl Decoder for ProtoCodec {
                     //         "Request package do not have request field",
                     //     ));
                     // }
-                    return Ok(Some((id, (meta.take_request(), controller, body))));
+                    let request = meta.take_request();
+                    // the deadline counts from the time the request arrives
+                    let timeout_ms = request.get_timeout_ms();
+                    if timeout_ms > 0 {
+                        controller.set_timeout(Duration::from_millis(timeout_ms as u64));
+                    }
+                    if request.get_log_id() != 0 {
+                        controller.set_log_id(request.get_log_id());
+                    }
+                    let flag = CancelFlag::default();
+                    controller.set_cancel_flag(flag.clone());
+                    self.cancel_flags.insert(id, flag);
+                    return Ok(Some((id, (request, controller, body))));
                 }
                 Err(ProtocolError::NeedMoreBytes) => return Ok(None),
                 Err(ProtocolError::TryOthers) => {
@@ -157,6 +184,7 @@ impl Encoder for ProtoCodec {
     fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
         let scheme = &self.schemes[self.cached_scheme];
         let (id, (resp_meta, controller, body)) = msg;
+        self.cancel_flags.remove(&id);
         let mut meta = RpcMeta::new();
         meta.set_response(resp_meta);
         meta.set_correlation_id(id);
@@ -164,6 +192,15 @@ impl Encoder for ProtoCodec {
     }
 }
 
+/// A message sent by the client
+#[derive(Clone, Debug)]
+pub enum ClientMessage {
+    /// Call a method
+    Request(RpcRequestMeta, Bytes),
+    /// Tell the server that the client gives up the request
+    Cancel,
+}
+
 /// Client side codec
 pub struct ProtoCodecClient {
     scheme: Box<RpcProtocol>,
@@ -193,7 +230,7 @@ impl Decoder for ProtoCodecClient {
             Ok((id, (mut meta, _, body))) => {
                 if !meta.has_response() {
                     return Err(io::Error::new(
-                        io::ErrorKind::Other,
+                        io::ErrorKind::InvalidData,
                         "Response package do not have response field",
                     ));
                 }
@@ -203,7 +240,7 @@ impl Decoder for ProtoCodecClient {
             Err(ProtocolError::TryOthers) | Err(ProtocolError::AbsolutelyWrong) => {
                 error!("Decode response package failed, invalid package or wrong protocol");
                 return Err(io::Error::new(
-                    io::ErrorKind::Other,
+                    io::ErrorKind::InvalidData,
                     "Invalid package or wrong protocol",
                 ));
             }
@@ -212,13 +249,22 @@ impl Decoder for ProtoCodecClient {
 }
 
 impl Encoder for ProtoCodecClient {
-    type Item = (RequestId, (RpcRequestMeta, Bytes));
+    type Item = (RequestId, ClientMessage);
     type Error = io::Error;
 
     fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
-        let (id, (request_meta, body)) = msg;
+        let (id, msg) = msg;
         let mut meta = RpcMeta::new();
-        meta.set_request(request_meta);
+        let body = match msg {
+            ClientMessage::Request(request_meta, body) => {
+                meta.set_request(request_meta);
+                body
+            }
+            ClientMessage::Cancel => {
+                meta.set_cancel(true);
+                Bytes::new()
+            }
+        };
         meta.set_correlation_id(id);
 
         self.scheme
@@ -1,34 +1,76 @@
 use bytes::{Buf, BufMut};
-use futures::{Async, Poll};
+use futures::{Async, Future, Poll};
 use std::io::{self, Read, Write};
 use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_timer::{Sleep, Timer};
 
-use super::Second;
+use super::{Second, ShutdownSignal};
+
+/// Count a connection as open until it is dropped
+pub struct OpenConnection(Arc<AtomicUsize>);
+
+impl OpenConnection {
+    pub fn new(count: Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        OpenConnection(count)
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
 pub struct TcpConnection<T> {
     io: T,
     timer: Timer,
     idle_timeout: Sleep,
     idle_secs: Second,
+    shutdown: ShutdownSignal,
+    _open: OpenConnection,
 }
 
 impl<T> TcpConnection<T> {
-    pub fn new(io: T, timer: Timer, idle: Second) -> Self {
+    pub fn new(
+        io: T,
+        timer: Timer,
+        idle: Second,
+        shutdown: ShutdownSignal,
+        open: OpenConnection,
+    ) -> Self {
         let init_timeout = timer.sleep(Duration::from_secs(idle));
         TcpConnection {
             io,
             timer,
             idle_timeout: init_timeout,
             idle_secs: idle,
+            shutdown,
+            _open: open,
+        }
+    }
+
+    /// Whether the server is shutting down, the task is woken up once it is.
+    fn is_shutting_down(&mut self) -> bool {
+        match self.shutdown.poll() {
+            Ok(Async::NotReady) => false,
+            _ => true,
         }
     }
 }
 
 impl<T: Read> Read for TcpConnection<T> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // no more requests are read, the connection is closed once the
+        // pending ones are answered
+        if self.is_shutting_down() {
+            trace!("Server closed a connection due to shutdown");
+            return Ok(0);
+        }
         self.io.read(buf)
     }
 }
@@ -55,6 +97,10 @@ impl<T: AsyncRead> AsyncRead for TcpConnection<T> {
             trace!("Server closed a connection due to idle timeout");
             return Ok(Async::Ready(0));
         }
+        if self.is_shutting_down() {
+            trace!("Server closed a connection due to shutdown");
+            return Ok(Async::Ready(0));
+        }
 
         let read = try_ready!(self.io.read_buf(buf));
         // reset timeout
@@ -0,0 +1,414 @@
+//! Admission control of incoming requests
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use time::secs_f64;
+
+/// Weight of the latest window in the moving average of the throughput
+const QPS_EMA_FACTOR: f64 = 0.1;
+
+/// Every this many windows, the no-load latency is measured again
+const REMEASURE_WINDOWS: usize = 30;
+
+/// Adjust the concurrency limit of each method to what the server can take
+///
+/// The latency and the throughput of the answered requests are sampled in
+/// windows. The lowest latency approximates the latency without load, so the
+/// server can process about `max_qps * min_latency` requests at the same time
+/// without queueing them. The limit is set a bit above that, to find out if
+/// the server can do more. Once the requests queue up, their latency grows
+/// but the throughput does not, and the requests over the limit are rejected
+/// before the latency gets out of hand.
+///
+/// Every 30 windows the limit is lowered below the estimate by the same
+/// ratio for one window, and the latency without load is measured again.
+///
+/// # Examples
+///
+/// ```
+/// use copra::server::AutoConcurrency;
+/// use std::time::Duration;
+///
+/// let auto = AutoConcurrency::new()
+///     .initial(20)
+///     .window(Duration::from_millis(500), 50);
+/// ```
+#[derive(Clone, Debug)]
+pub struct AutoConcurrency {
+    initial: usize,
+    window: Duration,
+    min_samples: usize,
+    explore_ratio: f64,
+}
+
+impl AutoConcurrency {
+    /// Create a limiter with the default settings.
+    pub fn new() -> Self {
+        AutoConcurrency {
+            initial: 40,
+            window: Duration::from_secs(1),
+            min_samples: 100,
+            explore_ratio: 0.3,
+        }
+    }
+
+    /// Set the limit before the first window is sampled.
+    ///
+    /// Default to 40.
+    pub fn initial(mut self, limit: usize) -> Self {
+        self.initial = limit.max(1);
+        self
+    }
+
+    /// Set how long a window lasts, and how many requests it needs to
+    /// adjust the limit. A window with fewer requests is discarded.
+    ///
+    /// Default to 1 second and 100.
+    pub fn window(mut self, window: Duration, min_samples: usize) -> Self {
+        self.window = window;
+        self.min_samples = min_samples.max(1);
+        self
+    }
+
+    /// Set how far above the estimated capacity the limit is.
+    ///
+    /// Default to 0.3.
+    pub fn explore_ratio(mut self, ratio: f64) -> Self {
+        self.explore_ratio = ratio;
+        self
+    }
+}
+
+impl Default for AutoConcurrency {
+    fn default() -> Self {
+        AutoConcurrency::new()
+    }
+}
+
+/// Samples of the current window, and the estimates so far
+#[derive(Debug)]
+struct AutoState {
+    config: AutoConcurrency,
+    limit: usize,
+    window_start: Instant,
+    succeeded: usize,
+    failed: usize,
+    total_latency: f64,
+    min_latency: Option<f64>,
+    max_qps: f64,
+    windows: usize,
+    remeasuring: bool,
+}
+
+impl AutoState {
+    fn new(config: AutoConcurrency, now: Instant) -> Self {
+        AutoState {
+            limit: config.initial,
+            config,
+            window_start: now,
+            succeeded: 0,
+            failed: 0,
+            total_latency: 0.0,
+            min_latency: None,
+            max_qps: 0.0,
+            windows: 0,
+            remeasuring: false,
+        }
+    }
+
+    /// Record a request answered at `now`, and return the new limit if the
+    /// window is over.
+    fn sample(&mut self, started: Instant, now: Instant, succeeded: bool) -> Option<usize> {
+        // admitted under an older limit
+        if started < self.window_start {
+            return None;
+        }
+        if succeeded {
+            self.succeeded += 1;
+            self.total_latency += secs_f64(now.duration_since(started));
+        } else {
+            self.failed += 1;
+        }
+
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < self.config.window {
+            return None;
+        }
+        let enough = self.succeeded + self.failed >= self.config.min_samples;
+        if enough {
+            self.update(secs_f64(elapsed));
+        }
+        self.window_start = now;
+        self.succeeded = 0;
+        self.failed = 0;
+        self.total_latency = 0.0;
+        if enough {
+            Some(self.limit)
+        } else {
+            None
+        }
+    }
+
+    fn update(&mut self, elapsed: f64) {
+        if self.succeeded == 0 {
+            self.limit = (self.limit / 2).max(1);
+            return;
+        }
+        let latency = self.total_latency / self.succeeded as f64;
+        let qps = self.succeeded as f64 / elapsed;
+
+        let min_latency = match self.min_latency {
+            Some(min_latency) if !self.remeasuring => min_latency.min(latency),
+            _ => latency,
+        };
+        self.min_latency = Some(min_latency);
+        self.max_qps = if qps >= self.max_qps {
+            qps
+        } else {
+            self.max_qps * (1.0 - QPS_EMA_FACTOR) + qps * QPS_EMA_FACTOR
+        };
+
+        self.windows += 1;
+        self.remeasuring = self.windows % REMEASURE_WINDOWS == 0;
+        let ratio = if self.remeasuring {
+            1.0 - self.config.explore_ratio
+        } else {
+            1.0 + self.config.explore_ratio
+        };
+        let limit = (self.max_qps * min_latency * ratio).ceil() as usize;
+        self.limit = limit.max(1);
+    }
+}
+
+#[derive(Debug)]
+struct LimiterInner {
+    max: AtomicUsize,
+    in_flight: AtomicUsize,
+    auto: Option<Mutex<AutoState>>,
+}
+
+/// Limit the number of requests processed at the same time
+#[derive(Debug)]
+pub(crate) struct ConcurrencyLimiter {
+    inner: Arc<LimiterInner>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max: usize) -> Self {
+        ConcurrencyLimiter::with_state(max, None)
+    }
+
+    /// Create a limiter adjusted by `config`.
+    pub fn auto(config: &AutoConcurrency) -> Self {
+        let state = AutoState::new(config.clone(), Instant::now());
+        ConcurrencyLimiter::with_state(config.initial, Some(Mutex::new(state)))
+    }
+
+    fn with_state(max: usize, auto: Option<Mutex<AutoState>>) -> Self {
+        ConcurrencyLimiter {
+            inner: Arc::new(LimiterInner {
+                max: AtomicUsize::new(max),
+                in_flight: AtomicUsize::new(0),
+                auto,
+            }),
+        }
+    }
+
+    /// Take a slot, or `None` if all of them are taken.
+    pub fn acquire(&self) -> Option<Permit> {
+        let inner = &self.inner;
+        if inner.in_flight.fetch_add(1, Ordering::SeqCst) >= inner.max.load(Ordering::SeqCst) {
+            inner.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Permit {
+            inner: inner.clone(),
+            started: Instant::now(),
+        })
+    }
+}
+
+/// A slot of a limiter, released when dropped
+#[derive(Debug)]
+pub(crate) struct Permit {
+    inner: Arc<LimiterInner>,
+    started: Instant,
+}
+
+impl Permit {
+    /// Release the slot of an answered request, its latency is sampled by an
+    /// adaptive limiter.
+    pub fn finish(self, succeeded: bool) {
+        if let Some(ref auto) = self.inner.auto {
+            let limit = auto.lock()
+                .unwrap()
+                .sample(self.started, Instant::now(), succeeded);
+            if let Some(limit) = limit {
+                debug!("Concurrency limit is adjusted to {}", limit);
+                self.inner.max.store(limit, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.inner.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Why a request is not admitted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Rejection {
+    Server,
+    Method,
+}
+
+/// The server-wide limit, and the limits of each method
+#[derive(Debug)]
+pub(crate) struct Admission {
+    server: Option<ConcurrencyLimiter>,
+    methods: HashMap<String, HashMap<String, ConcurrencyLimiter>>,
+}
+
+impl Admission {
+    pub fn new(server: Option<usize>, methods: &HashMap<String, HashMap<String, usize>>) -> Self {
+        let methods = methods
+            .iter()
+            .map(|(service_name, limits)| {
+                let limiters = limits
+                    .iter()
+                    .map(|(method_name, &max)| (method_name.clone(), ConcurrencyLimiter::new(max)))
+                    .collect();
+                (service_name.clone(), limiters)
+            })
+            .collect();
+        Admission {
+            server: server.map(ConcurrencyLimiter::new),
+            methods,
+        }
+    }
+
+    /// Adjust the limits of `methods` by `config`, unless they are set.
+    pub fn auto(mut self, config: &AutoConcurrency, methods: Vec<(String, String)>) -> Self {
+        for (service_name, method_name) in methods {
+            self.methods
+                .entry(service_name)
+                .or_default()
+                .entry(method_name)
+                .or_insert_with(|| ConcurrencyLimiter::auto(config));
+        }
+        self
+    }
+
+    /// Admit a request to the method, the permits are held until it is
+    /// answered.
+    pub fn admit(
+        &self,
+        service_name: &str,
+        method_name: &str,
+    ) -> Result<Vec<Permit>, Rejection> {
+        let mut permits = Vec::with_capacity(2);
+        if let Some(ref limiter) = self.server {
+            permits.push(limiter.acquire().ok_or(Rejection::Server)?);
+        }
+        let limiter = self.methods
+            .get(service_name)
+            .and_then(|limiters| limiters.get(method_name));
+        if let Some(limiter) = limiter {
+            permits.push(limiter.acquire().ok_or(Rejection::Method)?);
+        }
+        Ok(permits)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn release_on_drop() {
+        let limiter = ConcurrencyLimiter::new(2);
+        let first = limiter.acquire().unwrap();
+        let _second = limiter.acquire().unwrap();
+        assert!(limiter.acquire().is_none());
+        drop(first);
+        assert!(limiter.acquire().is_some());
+    }
+
+    #[test]
+    fn server_and_method_limits() {
+        let mut methods = HashMap::new();
+        let mut limits = HashMap::new();
+        limits.insert("echo".to_string(), 1);
+        methods.insert("Echo".to_string(), limits);
+        let admission = Admission::new(Some(2), &methods);
+
+        let echo = admission.admit("Echo", "echo").unwrap();
+        assert_eq!(admission.admit("Echo", "echo").unwrap_err(), Rejection::Method);
+        // the rejected request does not keep the server-wide slot
+        let other = admission.admit("Echo", "other").unwrap();
+        assert_eq!(admission.admit("Echo", "other").unwrap_err(), Rejection::Server);
+        drop((echo, other));
+        assert!(admission.admit("Echo", "echo").is_ok());
+    }
+
+    /// Serve a window of requests by a handler that takes 10ms and handles
+    /// 4 requests at once, the others are queued.
+    fn serve_window(state: &mut AutoState, start: Instant) -> (Instant, f64) {
+        let concurrency = state.limit as f64;
+        let qps = concurrency.min(4.0) / 0.01;
+        let latency = 0.01 * (concurrency / 4.0).max(1.0);
+        let mut i = 0.0;
+        loop {
+            let started = start + Duration::from_nanos((i / qps * 1e9) as u64);
+            let now = started + Duration::from_nanos((latency * 1e9) as u64);
+            if state.sample(started, now, true).is_some() {
+                return (now, latency);
+            }
+            i += 1.0;
+        }
+    }
+
+    #[test]
+    fn adjust_to_slow_handler() {
+        let start = Instant::now();
+        let mut state = AutoState::new(AutoConcurrency::new(), start);
+
+        // overloaded at first
+        let (mut now, latency) = serve_window(&mut state, start);
+        assert_eq!(latency, 0.1);
+        let mut latencies = Vec::new();
+        for _ in 0..300 {
+            let (end, latency) = serve_window(&mut state, now);
+            now = end;
+            latencies.push(latency);
+        }
+        // about as many requests as the handler can take at once
+        assert!(state.limit >= 4 && state.limit <= 8, "limit {}", state.limit);
+        let latest = &latencies[latencies.len() - 30..];
+        assert!(latest.iter().all(|&latency| latency <= 0.02));
+        assert!((state.min_latency.unwrap() - 0.01).abs() < 1e-3);
+    }
+
+    #[test]
+    fn shrink_on_failures() {
+        let config = AutoConcurrency::new()
+            .initial(10)
+            .window(Duration::from_secs(1), 2);
+        let start = Instant::now();
+        let mut state = AutoState::new(config, start);
+
+        // too few requests, the window is discarded
+        assert_eq!(state.sample(start, start + Duration::from_secs(1), false), None);
+        let start = start + Duration::from_secs(1);
+        assert_eq!(state.sample(start, start, false), None);
+        // admitted before the window
+        let before = start - Duration::from_millis(1);
+        assert_eq!(state.sample(before, start + Duration::from_secs(1), false), None);
+        assert_eq!(state.sample(start, start + Duration::from_secs(1), false), Some(5));
+    }
+}
@@ -17,37 +17,48 @@
 //! // add some service to the registry
 //!
 //! let server = ServerBuilder::new("127.0.0.1:8000", registry).build()?;
+//!
+//! // call `handle.shutdown(grace)` from another thread to stop the server
+//! let handle = server.handle();
 //! server.start();
 //! # Ok(())
 //! # }
 //! ```
 
 use bytes::Bytes;
-use tokio_core::reactor::Remote;
-use tokio_proto::TcpServer;
-use tokio_proto::multiplex::Multiplex;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Remote};
+use tokio_proto::BindServer;
 use tokio_service::{NewService, Service};
 use tokio_timer::Timer;
 use std::error::Error;
 use std::fmt;
 use std::io;
-use std::net::AddrParseError;
-use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
-use futures::{Future, IntoFuture, Stream};
-use futures::future::Executor;
+use std::net::{self, AddrParseError, SocketAddr};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use futures::{future, Future, Stream};
+use futures::future::{Either, Executor, Shared};
+use futures::sync::oneshot;
 
 use controller::Controller;
 use protocol::Protocol;
+use protocol::http::HttpStatus;
 use dispatcher::ServiceRegistry;
-use service::MethodError;
+use service::{ErrorCode, MethodError, MethodFuture};
 use message::RpcResponseMeta;
 use message::{RequestPackage, ResponsePackage};
-use monitor::ThroughputMaintainer;
+use monitor::{DispatchFailures, ThroughputMaintainer};
+
+pub use self::limiter::AutoConcurrency;
 
+use self::limiter::{Admission, Rejection};
 use self::protocol::MetaServerProtocol;
 
 mod connection;
+mod limiter;
 mod protocol;
 
 type Second = u64;
@@ -57,11 +68,95 @@ type MetaServiceFuture = Box<Future<Item = ResponsePackage, Error = io::Error>>;
 #[derive(Clone)]
 struct MetaService {
     registry: Arc<ServiceRegistry>,
+    admission: Arc<Admission>,
+    failures: Arc<DispatchFailures>,
+    timer: Timer,
 }
 
 impl MetaService {
-    pub fn new(registry: Arc<ServiceRegistry>) -> Self {
-        MetaService { registry }
+    pub fn new(
+        registry: Arc<ServiceRegistry>,
+        admission: Arc<Admission>,
+        failures: Arc<DispatchFailures>,
+        timer: Timer,
+    ) -> Self {
+        MetaService {
+            registry,
+            admission,
+            failures,
+            timer,
+        }
+    }
+
+    /// Fail `fut` with a timeout error if it is not done in `remaining`, the
+    /// handler is dropped then.
+    fn with_deadline(&self, fut: MethodFuture, remaining: Duration) -> MethodFuture {
+        let expired = self.timer.sleep(remaining).then(|result| match result {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                warn!("Request timer failed: {}", e);
+                Err(())
+            }
+        });
+        let fut = fut.select2(expired).then(|result| -> MethodFuture {
+            match result {
+                Ok(Either::A((resp, _))) => Box::new(future::ok(resp)),
+                Err(Either::A((e, _))) => Box::new(future::err(e)),
+                Ok(Either::B(_)) => {
+                    debug!("Request deadline exceeded, the handler is cancelled");
+                    let error = MethodError::new(ErrorCode::Timeout, "deadline exceeded");
+                    Box::new(future::err(error))
+                }
+                // no timer, wait for the handler
+                Err(Either::B((_, fut))) => fut,
+            }
+        });
+        Box::new(fut)
+    }
+
+    /// Answer a request to a missing service or method.
+    fn not_found(
+        &self,
+        service_name: &str,
+        method_name: &str,
+        controller: &Controller,
+    ) -> ResponsePackage {
+        let error = if self.registry.has_service(service_name) {
+            warn!(
+                "Requested method {}::{} is not found",
+                service_name, method_name
+            );
+            self.failures.add_no_such_method();
+            let text = format!("{}::{} is not found", service_name, method_name);
+            MethodError::new(ErrorCode::NoSuchMethod, text)
+        } else {
+            warn!("Requested service {} is not found", service_name);
+            self.failures.add_no_such_service();
+            let text = format!("{} is not found", service_name);
+            MethodError::new(ErrorCode::NoSuchService, text)
+        };
+        reject(&error, controller, HttpStatus::NotFound)
+    }
+
+    /// Answer a request over a concurrency limit.
+    fn overloaded(
+        &self,
+        service_name: &str,
+        method_name: &str,
+        rejection: Rejection,
+        controller: &Controller,
+    ) -> ResponsePackage {
+        self.failures.add_overloaded();
+        let text = match rejection {
+            Rejection::Server => "server is overloaded".to_string(),
+            Rejection::Method => format!("{}::{} is overloaded", service_name, method_name),
+        };
+        debug!(
+            "Request to {}::{} is rejected: {}",
+            service_name, method_name, text
+        );
+        let error = MethodError::new(ErrorCode::Overloaded, text);
+        reject(&error, controller, HttpStatus::ServiceUnavailable)
     }
 }
 
@@ -73,25 +168,42 @@ impl Service for MetaService {
 
     fn call(&self, req: Self::Request) -> Self::Future {
         let (meta, controller, body) = req;
-        let service = {
-            let service_name = meta.get_service_name();
-            let method_name = meta.get_method_name();
-            self.registry
-                .get_method(service_name, method_name)
-                .ok_or(MethodError::UnknownError)
-                .map_err(|e| {
-                    warn!(
-                        "Requested method {}::{} is not found",
-                        service_name, method_name
-                    );
-                    e
-                })
-                .into_future()
+        let service_name = meta.get_service_name();
+        let method_name = meta.get_method_name();
+        let service = match self.registry.get_method(service_name, method_name) {
+            Some(service) => service,
+            None => {
+                let response = self.not_found(service_name, method_name, &controller);
+                return Box::new(future::ok(response));
+            }
+        };
+        let permits = match self.admission.admit(service_name, method_name) {
+            Ok(permits) => permits,
+            Err(rejection) => {
+                let response = self.overloaded(service_name, method_name, rejection, &controller);
+                return Box::new(future::ok(response));
+            }
         };
-        let response = service
-            .and_then(|service| service.call((body, controller)))
-            .then(|resp| result_to_errno(resp));
-        Box::new(response)
+        let remaining = controller.remaining();
+        let response = match remaining {
+            Some(remaining) if remaining == Duration::from_secs(0) => {
+                debug!(
+                    "Request to {}::{} expired before it is processed",
+                    service_name, method_name
+                );
+                let error = MethodError::new(ErrorCode::Timeout, "deadline exceeded");
+                Box::new(future::err(error))
+            }
+            Some(remaining) => self.with_deadline(service.call((body, controller)), remaining),
+            None => service.call((body, controller)),
+        };
+        Box::new(response.then(move |result| {
+            // the request is done, free the slots
+            for permit in permits {
+                permit.finish(result.is_ok());
+            }
+            result_to_errno(result)
+        }))
     }
 }
 
@@ -153,6 +265,9 @@ pub struct ServerBuilder<'a> {
     idle_secs: Option<Second>,
     remote: Option<Remote>,
     throughput: Option<Arc<AtomicUsize>>,
+    dispatch_failures: Option<Arc<DispatchFailures>>,
+    max_concurrency: Option<usize>,
+    auto_concurrency: Option<AutoConcurrency>,
 }
 
 impl<'a> ServerBuilder<'a> {
@@ -166,6 +281,9 @@ impl<'a> ServerBuilder<'a> {
             idle_secs: None,
             remote: None,
             throughput: None,
+            dispatch_failures: None,
+            max_concurrency: None,
+            auto_concurrency: None,
         }
     }
 
@@ -202,6 +320,34 @@ impl<'a> ServerBuilder<'a> {
         self
     }
 
+    /// Count requests to missing services and methods, and the ones over
+    /// the concurrency limits, in the shared counters `failures`.
+    pub fn dispatch_failures(mut self, failures: Arc<DispatchFailures>) -> Self {
+        self.dispatch_failures = Some(failures);
+        self
+    }
+
+    /// Process at most `limit` requests at the same time.
+    ///
+    /// The requests over the limit are rejected at once with
+    /// `ErrorCode::Overloaded`, and counted in `DispatchFailures`. Limits of
+    /// single methods are set with `ServiceRegistry::max_concurrency`.
+    /// Default to no limit.
+    pub fn max_concurrency(mut self, limit: usize) -> Self {
+        self.max_concurrency = Some(limit);
+        self
+    }
+
+    /// Adjust the concurrency limit of each method to the measured capacity
+    /// of the server, see `AutoConcurrency`.
+    ///
+    /// The methods with a limit set by `ServiceRegistry::max_concurrency`
+    /// keep it. Default to `None`, the limits are not adjusted.
+    pub fn auto_concurrency(mut self, auto: AutoConcurrency) -> Self {
+        self.auto_concurrency = Some(auto);
+        self
+    }
+
     /// Consume the builder and build.
     pub fn build(self) -> Result<Server, ServerBuildError> {
         let finished = Arc::new(AtomicUsize::new(0));
@@ -210,46 +356,89 @@ impl<'a> ServerBuilder<'a> {
             .unwrap_or(vec![Protocol::Brpc, Protocol::Http]);
         let idle_secs = self.idle_secs.unwrap_or(60);
         let throughput = self.throughput.unwrap_or(Arc::new(AtomicUsize::new(0)));
+        let dispatch_failures = self.dispatch_failures.unwrap_or_default();
 
         let timer = Timer::default();
         let socket_addr = self.addr.parse()?;
 
-        let mut listener = TcpServer::new(
-            MetaServerProtocol::new(protocols, timer.clone(), idle_secs, finished.clone()),
-            socket_addr,
+        let (signal, shutdown) = oneshot::channel();
+        let shutdown = shutdown.shared();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let protocol = MetaServerProtocol::new(
+            protocols,
+            timer.clone(),
+            idle_secs,
+            finished.clone(),
+            shutdown.clone(),
+            connections.clone(),
         );
-        listener.threads(threads);
+
+        let mut admission =
+            Admission::new(self.max_concurrency, self.services.concurrency_limits());
+        if let Some(ref auto) = self.auto_concurrency {
+            admission = admission.auto(auto, self.services.method_names());
+        }
 
         info!("Server listening: {}", socket_addr);
         let server = Server {
             services: Arc::new(self.services),
-            listener,
+            admission: Arc::new(admission),
+            protocol: Arc::new(protocol),
+            addr: socket_addr,
+            threads: threads.max(1),
             throughput,
+            dispatch_failures,
             finished,
             timer,
             remote: self.remote,
+            shutdown,
+            connections,
+            handle: ServerHandle::new(signal),
         };
 
         Ok(server)
     }
 }
 
+/// Resolve to the grace period once the server is asked to shut down
+type ShutdownSignal = Shared<oneshot::Receiver<Duration>>;
+
 /// A RPC server
 #[derive(Debug)]
 pub struct Server {
     services: Arc<ServiceRegistry>,
-    listener: TcpServer<Multiplex, MetaServerProtocol>,
+    admission: Arc<Admission>,
+    protocol: Arc<MetaServerProtocol>,
+    addr: SocketAddr,
+    threads: usize,
     finished: Arc<AtomicUsize>,
     throughput: Arc<AtomicUsize>,
+    dispatch_failures: Arc<DispatchFailures>,
     timer: Timer,
     remote: Option<Remote>,
+    shutdown: ShutdownSignal,
+    connections: Arc<AtomicUsize>,
+    handle: ServerHandle,
 }
 
 impl Server {
     /// Run the server.
     ///
-    /// This method will block the current thread forever
+    /// This method blocks the current thread until the server is shut down,
+    /// see `Server::handle`. It returns at once if the server is shut down
+    /// before it starts.
     pub fn start(&self) {
+        {
+            // checked along with `running`, so that a shutdown either sees
+            // the server running or keeps it from starting
+            let mut running = self.handle.inner.running.lock().unwrap();
+            if self.handle.inner.signal.lock().unwrap().is_none() {
+                info!("Server is shut down before it starts: {}", self.addr);
+                return;
+            }
+            *running = true;
+        }
+
         if let Some(ref remote) = self.remote {
             let maintainer = ThroughputMaintainer::new(
                 self.timer.clone(),
@@ -259,7 +448,133 @@ impl Server {
             remote.execute(maintainer.for_each(|_| Ok(()))).unwrap();
         }
 
-        self.listener.serve(MetaService::new(self.services.clone()))
+        let listener = net::TcpListener::bind(self.addr).unwrap();
+        let workers: Vec<_> = (1..self.threads)
+            .map(|i| {
+                let worker = self.worker();
+                let listener = listener.try_clone().unwrap();
+                thread::Builder::new()
+                    .name(format!("worker{}", i))
+                    .spawn(move || worker.run(listener))
+                    .unwrap()
+            })
+            .collect();
+        self.worker().run(listener);
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        info!("Server stopped: {}", self.addr);
+
+        *self.handle.inner.running.lock().unwrap() = false;
+        self.handle.inner.stopped.notify_all();
+    }
+
+    /// Get a handle to shut down the server from another thread.
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    fn worker(&self) -> Worker {
+        Worker {
+            addr: self.addr,
+            protocol: self.protocol.clone(),
+            service: MetaService::new(
+                self.services.clone(),
+                self.admission.clone(),
+                self.dispatch_failures.clone(),
+                self.timer.clone(),
+            ),
+            timer: self.timer.clone(),
+            shutdown: self.shutdown.clone(),
+            connections: self.connections.clone(),
+        }
+    }
+}
+
+/// Serve connections on an event loop
+struct Worker {
+    addr: SocketAddr,
+    protocol: Arc<MetaServerProtocol>,
+    service: MetaService,
+    timer: Timer,
+    shutdown: ShutdownSignal,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Worker {
+    /// Accept connections until the server is shut down, then wait for the
+    /// connections to be closed.
+    fn run(self, listener: net::TcpListener) {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = TcpListener::from_listener(listener, &self.addr, &handle).unwrap();
+
+        let (protocol, service) = (self.protocol, self.service);
+        let accept = listener.incoming().for_each(move |(socket, _)| {
+            protocol.bind_server(&handle, socket, service.new_service()?);
+            Ok(())
+        });
+        // the listener is dropped along with `accept`
+        let grace = match core.run(accept.select2(self.shutdown)) {
+            Ok(Either::B((grace, _))) => *grace,
+            Err(Either::B(_)) => Duration::from_secs(0),
+            Ok(Either::A(_)) => return,
+            Err(Either::A((e, _))) => {
+                error!("Failed to accept connections: {}", e);
+                return;
+            }
+        };
+
+        let connections = self.connections;
+        let drained = self.timer
+            .interval(Duration::from_millis(200))
+            .take_while(move |_| Ok(connections.load(Ordering::SeqCst) > 0))
+            .for_each(|_| Ok(()));
+        // connections still open after the grace period are dropped along
+        // with the event loop
+        let _ = core.run(drained.select2(self.timer.sleep(grace)));
+    }
+}
+
+/// Shut down a running server
+#[derive(Clone, Debug)]
+pub struct ServerHandle {
+    inner: Arc<HandleInner>,
+}
+
+#[derive(Debug)]
+struct HandleInner {
+    signal: Mutex<Option<oneshot::Sender<Duration>>>,
+    running: Mutex<bool>,
+    stopped: Condvar,
+}
+
+impl ServerHandle {
+    fn new(signal: oneshot::Sender<Duration>) -> Self {
+        ServerHandle {
+            inner: Arc::new(HandleInner {
+                signal: Mutex::new(Some(signal)),
+                running: Mutex::new(false),
+                stopped: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Shut down the server, and wait for `Server::start` to return.
+    ///
+    /// The server stops accepting connections at once, and closes the idle
+    /// ones. The other connections are closed once their requests in flight
+    /// are answered, or when `grace` has passed. If the server has not
+    /// started yet, it never will.
+    pub fn shutdown(&self, grace: Duration) {
+        let mut running = self.inner.running.lock().unwrap();
+        if let Some(signal) = self.inner.signal.lock().unwrap().take() {
+            info!("Server is shutting down");
+            let _ = signal.send(grace);
+        }
+        while *running {
+            running = self.inner.stopped.wait(running).unwrap();
+        }
     }
 }
 
@@ -272,10 +587,22 @@ fn result_to_errno(
             meta.set_error_code(0);
             Ok((meta, controller, body))
         })
-        .or_else(|_| {
-            let mut meta = RpcResponseMeta::new();
-            meta.set_error_code(1);
-            meta.set_error_text("Unknown error".to_string());
-            Ok((meta, Controller::default(), Bytes::new()))
-        })
+        .or_else(|e| Ok(error_to_errno(&e, Controller::default())))
+}
+
+/// Answer a request that is not dispatched to a handler, `status` is used if
+/// it came in over http.
+fn reject(e: &MethodError, controller: &Controller, status: HttpStatus) -> ResponsePackage {
+    let mut response_controller = Controller::default();
+    if controller.http_url.is_some() {
+        response_controller.status = Some(status);
+    }
+    error_to_errno(e, response_controller)
+}
+
+fn error_to_errno(e: &MethodError, controller: Controller) -> ResponsePackage {
+    let mut meta = RpcResponseMeta::new();
+    meta.set_error_code(e.code().errno());
+    meta.set_error_text(e.text().to_string());
+    (meta, controller, Bytes::new())
 }
@@ -10,8 +10,8 @@ use monitor::TrafficCounting;
 use protocol::{BrpcProtocol, HttpProtocol, ProtoCodec, Protocol, RpcProtocol};
 use message::{RequestPackage, ResponsePackage};
 
-use super::connection::TcpConnection;
-use super::Second;
+use super::connection::{OpenConnection, TcpConnection};
+use super::{Second, ShutdownSignal};
 
 #[derive(Debug)]
 pub struct MetaServerProtocol {
@@ -19,6 +19,8 @@ pub struct MetaServerProtocol {
     timer: Timer,
     idle_secs: Second,
     finished: Arc<AtomicUsize>,
+    shutdown: ShutdownSignal,
+    connections: Arc<AtomicUsize>,
 }
 
 impl MetaServerProtocol {
@@ -27,6 +29,8 @@ impl MetaServerProtocol {
         timer: Timer,
         idle_secs: Second,
         finished: Arc<AtomicUsize>,
+        shutdown: ShutdownSignal,
+        connections: Arc<AtomicUsize>,
     ) -> Self {
         let protocols: Vec<_> = protocols
             .iter()
@@ -41,6 +45,8 @@ impl MetaServerProtocol {
             timer,
             idle_secs,
             finished,
+            shutdown,
+            connections,
         }
     }
 }
@@ -56,7 +62,13 @@ where
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
         trace!("New connection established");
-        let connection = TcpConnection::new(io, self.timer.clone(), self.idle_secs);
+        let connection = TcpConnection::new(
+            io,
+            self.timer.clone(),
+            self.idle_secs,
+            self.shutdown.clone(),
+            OpenConnection::new(self.connections.clone()),
+        );
         let codec = ProtoCodec::new(self.protocols.as_slice());
         let transport = TrafficCounting::new(self.finished.clone(), connection.framed(codec));
 
@@ -5,6 +5,7 @@ use futures::{Future, IntoFuture};
 use std::error::Error;
 use std::fmt;
 use std::io;
+use std::sync::Arc;
 use tokio_service::NewService;
 
 use controller::Controller;
@@ -90,38 +91,192 @@ where
     }
 }
 
-// TODO: seperate this into server error and stub error
-/// [WIP] Error return by service providers
-#[derive(Clone, Debug, PartialEq)]
-pub enum MethodError {
-    /// [WIP] Other errors that might be worth discussion
-    UnknownError,
-    /// Failed to decode message
-    CodecError,
+/// Kind of failure of an RPC
+///
+/// The code is written into the response meta by the server, so the client
+/// sees the same code as the server does. Codes 1 and 1000 to 1999 are
+/// reserved, the other non-zero codes are left to applications. An
+/// `Application` error with code 0 or a reserved code is sent as `Unknown`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// Failed for a reason not listed here
+    Unknown,
+    /// The request did not finish before its deadline
+    Timeout,
+    /// Failed to send the request or to receive the response
+    ConnectionFailed,
+    /// The server does not provide the requested service
+    NoSuchService,
+    /// The service does not provide the requested method
+    NoSuchMethod,
+    /// Failed to encode or decode a message
+    Codec,
+    /// Too many requests are in flight to take a new one
+    Overloaded,
+    /// The handler failed with an error code of the application
+    Application(i32),
 }
 
-impl fmt::Display for MethodError {
+impl ErrorCode {
+    /// The code put in `RpcResponseMeta::error_code`.
+    pub fn errno(&self) -> i32 {
+        match *self {
+            ErrorCode::Unknown => 1,
+            ErrorCode::NoSuchService => 1001,
+            ErrorCode::NoSuchMethod => 1002,
+            ErrorCode::Codec => 1003,
+            ErrorCode::Timeout => 1008,
+            ErrorCode::ConnectionFailed => 1009,
+            ErrorCode::Overloaded => 1011,
+            ErrorCode::Application(code) if is_reserved(code) => 1,
+            ErrorCode::Application(code) => code,
+        }
+    }
+
+    /// Read the code from `RpcResponseMeta::error_code`, `None` if the
+    /// request succeeded.
+    pub fn from_errno(errno: i32) -> Option<Self> {
+        let code = match errno {
+            0 => return None,
+            1001 => ErrorCode::NoSuchService,
+            1002 => ErrorCode::NoSuchMethod,
+            1003 => ErrorCode::Codec,
+            1008 => ErrorCode::Timeout,
+            1009 => ErrorCode::ConnectionFailed,
+            1011 => ErrorCode::Overloaded,
+            1 | 1000..=1999 => ErrorCode::Unknown,
+            code => ErrorCode::Application(code),
+        };
+        Some(code)
+    }
+}
+
+/// Whether `errno` means something else than an application error
+fn is_reserved(errno: i32) -> bool {
+    match errno {
+        0 | 1 | 1000..=1999 => true,
+        _ => false,
+    }
+}
+
+impl fmt::Display for ErrorCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            MethodError::UnknownError => write!(f, "unknown error produced by server"),
-            MethodError::CodecError => write!(f, "failed to decode message"),
+            ErrorCode::Unknown => write!(f, "unknown error"),
+            ErrorCode::Timeout => write!(f, "request timed out"),
+            ErrorCode::ConnectionFailed => write!(f, "connection failed"),
+            ErrorCode::NoSuchService => write!(f, "no such service"),
+            ErrorCode::NoSuchMethod => write!(f, "no such method"),
+            ErrorCode::Codec => write!(f, "failed to encode or decode message"),
+            ErrorCode::Overloaded => write!(f, "server overloaded"),
+            ErrorCode::Application(code) => write!(f, "application error {}", code),
+        }
+    }
+}
+
+/// Error of a failed RPC
+///
+/// It is returned by service providers, and by the stubs on the client side.
+/// An error marked by the server keeps its code and text on the client side.
+#[derive(Clone, Debug)]
+pub struct MethodError {
+    code: ErrorCode,
+    text: String,
+    source: Option<Arc<Error + Send + Sync>>,
+}
+
+impl MethodError {
+    /// Create an error with a code and a message.
+    pub fn new<S: Into<String>>(code: ErrorCode, text: S) -> Self {
+        MethodError {
+            code,
+            text: text.into(),
+            source: None,
+        }
+    }
+
+    /// Create an error with a code and a message of the application.
+    ///
+    /// The code should be non-zero and out of the reserved ones, see
+    /// `ErrorCode`. Otherwise the error is made `Unknown`, as the client would
+    /// read it.
+    pub fn application<S: Into<String>>(code: i32, text: S) -> Self {
+        if is_reserved(code) {
+            return MethodError::new(ErrorCode::Unknown, text);
+        }
+        MethodError::new(ErrorCode::Application(code), text)
+    }
+
+    /// Attach the error that caused this one, its message is taken if no
+    /// message is given.
+    pub fn with_source<E>(mut self, source: E) -> Self
+    where
+        E: Error + Send + Sync + 'static,
+    {
+        if self.text.is_empty() {
+            self.text = source.to_string();
+        }
+        self.source = Some(Arc::new(source));
+        self
+    }
+
+    /// Kind of the failure.
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    /// Message about the failure, it is empty if no message is given.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl From<ErrorCode> for MethodError {
+    fn from(code: ErrorCode) -> Self {
+        MethodError::new(code, "")
+    }
+}
+
+/// Errors are equal if they have the same code and text.
+impl PartialEq for MethodError {
+    fn eq(&self, other: &Self) -> bool {
+        self.code == other.code && self.text == other.text
+    }
+}
+
+impl fmt::Display for MethodError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.text.is_empty() {
+            write!(f, "{}", self.code)
+        } else {
+            write!(f, "{}: {}", self.code, self.text)
         }
     }
 }
 
 impl Error for MethodError {
     fn description(&self) -> &str {
-        match *self {
-            MethodError::UnknownError => "unknown error",
-            MethodError::CodecError => "codec error",
+        match self.code {
+            ErrorCode::Unknown => "unknown error",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::ConnectionFailed => "connection failed",
+            ErrorCode::NoSuchService => "no such service",
+            ErrorCode::NoSuchMethod => "no such method",
+            ErrorCode::Codec => "codec error",
+            ErrorCode::Overloaded => "server overloaded",
+            ErrorCode::Application(_) => "application error",
         }
     }
+
+    fn cause(&self) -> Option<&Error> {
+        self.source.as_ref().map(|e| &**e as &Error)
+    }
 }
 
 #[doc(hidden)]
 impl From<ProtobufError> for MethodError {
-    fn from(_: ProtobufError) -> Self {
-        MethodError::CodecError
+    fn from(e: ProtobufError) -> Self {
+        MethodError::from(ErrorCode::Codec).with_source(e)
     }
 }
 
@@ -172,7 +327,6 @@ where
             .and_then(move |body| {
                 method
                     .call((body, controller))
-                    .map_err(|_| MethodError::UnknownError)
                     .and_then(move |(body, controller)| {
                         codec
                             .encode(body)
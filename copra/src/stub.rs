@@ -4,10 +4,10 @@ use bytes::Bytes;
 use futures::{Async, Future, Poll};
 
 use codec::MethodCodec;
-use channel::{Channel, ChannelFuture};
+use channel::{CallOptions, Channel, ChannelError, ChannelFuture};
 use load_balancer::CallInfo;
 use message::{RpcRequestMeta, RpcResponseMeta};
-use service::MethodError;
+use service::{ErrorCode, MethodError};
 
 type ResponsePackage = (RpcResponseMeta, Bytes);
 
@@ -32,15 +32,22 @@ impl<'a, C> RpcWrapper<'a, C>
 where
     C: MethodCodec + Clone,
 {
-    /// Issue a request and obtain a future.
-    pub fn call(&'a self, bundle: (C::Response, String, String)) -> StubFuture<C> {
+    /// Issue a request with per-call options and obtain a future.
+    pub fn call(
+        &'a self,
+        bundle: (C::Response, String, String),
+        options: CallOptions,
+    ) -> StubFuture<C> {
         let (req, service_name, method_name) = bundle;
         let channel_fut = match self.codec.encode(req) {
             Ok(body) => {
                 let mut meta = RpcRequestMeta::new();
                 meta.set_service_name(service_name);
                 meta.set_method_name(method_name);
-                Some(self.channel.call((meta, body)))
+                if let Some(log_id) = options.get_log_id() {
+                    meta.set_log_id(log_id);
+                }
+                Some(self.channel.call((meta, body), options))
             }
             Err(_) => None,
         };
@@ -49,21 +56,26 @@ where
     }
 }
 
+/// Extract the error marked by the server from a response meta.
+pub(crate) fn meta_to_error(meta: &RpcResponseMeta) -> Option<MethodError> {
+    ErrorCode::from_errno(meta.get_error_code())
+        .map(|code| MethodError::new(code, meta.get_error_text()))
+}
+
 fn errno_to_result(result: ResponsePackage) -> Result<Bytes, MethodError> {
     let (meta, body) = result;
-    let error_code = meta.get_error_code();
-    if error_code == 0 {
-        Ok(body)
-    } else {
-        error!("Server mark rpc to failed");
-        Err(MethodError::UnknownError)
+    match meta_to_error(&meta) {
+        None => Ok(body),
+        Some(e) => {
+            error!("Server mark rpc to failed: {}", e);
+            Err(e)
+        }
     }
 }
 
 /// A future that will resolve to a pair of response and RPC info
 #[derive(Debug)]
 pub struct StubFuture<C> {
-    start_usec: u64,
     inner: Option<ChannelFuture>,
     codec: C,
 }
@@ -71,17 +83,14 @@ pub struct StubFuture<C> {
 impl<C> StubFuture<C> {
     /// Create a new future.
     pub fn new(inner: Option<ChannelFuture>, codec: C) -> Self {
-        StubFuture {
-            start_usec: 0,
-            inner,
-            codec,
-        }
+        StubFuture { inner, codec }
     }
 }
 
 impl<C> Future for StubFuture<C>
 where
     C: MethodCodec,
+    MethodError: From<C::Error>,
 {
     type Item = (C::Request, RpcInfo);
 
@@ -91,26 +100,41 @@ where
         if let Some(ref mut channel) = self.inner {
             match channel.poll() {
                 Ok(Async::Ready((resp, fb_handle))) => {
-                    let body = errno_to_result(resp)?;
-                    let resp = self.codec
-                        .decode(body)
-                        .map_err(|_| MethodError::CodecError)?;
-                    let fb = CallInfo::new(self.start_usec, None);
-                    let info = RpcInfo;
-                    fb_handle.call(fb);
-
-                    Ok(Async::Ready((resp, info)))
+                    let result = errno_to_result(resp).and_then(|body| {
+                        self.codec
+                            .decode(body)
+                            .map_err(MethodError::from)
+                    });
+                    let start_usec = fb_handle.start_usec();
+                    fb_handle.call(CallInfo::new(start_usec, result.as_ref().err().cloned()));
+
+                    Ok(Async::Ready((result?, RpcInfo)))
                 }
                 Ok(Async::NotReady) => Ok(Async::NotReady),
-                // TODO: Add error convertion
-                Err(_) => Err(MethodError::UnknownError),
+                Err(e) => Err(e.into()),
             }
         } else {
-            Err(MethodError::CodecError)
+            Err(ErrorCode::Codec.into())
         }
     }
 }
 
+impl From<ChannelError> for MethodError {
+    fn from(e: ChannelError) -> Self {
+        let code = match e {
+            ChannelError::Timeout => ErrorCode::Timeout,
+            ChannelError::ConcurrencyLimitReached | ChannelError::RateLimited => {
+                ErrorCode::Overloaded
+            }
+            ChannelError::IoError(_)
+            | ChannelError::Disconnected(_)
+            | ChannelError::NoServerAvailable => ErrorCode::ConnectionFailed,
+            ChannelError::UnknownError => ErrorCode::Unknown,
+        };
+        MethodError::from(code).with_source(e)
+    }
+}
+
 /// [WIP] Information about how the RPC request has been processed
 #[derive(Clone, Debug, PartialEq)]
 pub struct RpcInfo;
@@ -0,0 +1,8 @@
+//! Helpers for time arithmetic
+
+use std::time::Duration;
+
+/// The number of seconds in `duration`, with the fraction.
+pub(crate) fn secs_f64(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
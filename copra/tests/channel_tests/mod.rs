@@ -1,16 +1,34 @@
 use bytes::{Bytes, BytesMut, BigEndian, BufMut};
-use copra::{ChannelBuilder, MethodError};
+use copra::{CallOptions, ChannelBuilder, ErrorCode, MethodError, ServerBuilder,
+            ServiceRegistry};
+use copra::channel::{CircuitBreaker, ConnectionState, ConnectionType, RateLimit,
+                     ReconnectPolicy, ServerHealth, Throttle};
 use copra::message::{ResponsePackage, RpcResponseMeta, RpcMeta};
+use copra::codec::ProtobufCodec;
 use copra::controller::Controller;
-use futures::Future;
+use copra::monitor::DispatchFailures;
+use copra::protocol::{BrpcProtocol, RpcProtocol};
+use copra::naming::{DnsNamingService, FileNamingService, NamingService, Resolve,
+                    ServerListStream, ServerNode};
+use copra::server::AutoConcurrency;
+use copra::stub::RpcWrapper;
+use futures::{future, stream, Async, Future, Poll, Stream};
+use futures::future::{Either, Loop};
 use mock::MockServerBuilder;
 use protobuf::{CodedOutputStream, Message};
-use std::time::Duration;
-use std::thread::spawn;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::thread::{sleep, spawn};
 use tokio_core::reactor::{Core, Handle};
+use tokio_timer::Timer;
 
 use generated::simple::Simple;
-use generated::simple_copra::EchoStub;
+use generated::simple_copra::{EchoRegistrant, EchoService, EchoStub};
 
 fn simple(i: i32, b: bool, s: &str) -> Simple {
     let mut msg = Simple::new();
@@ -102,7 +120,7 @@ fn bad_body_message() {
     let stub = EchoStub::new(&channel);
 
     let result = core.run(stub.echo(msg));
-    assert_eq!(result, Err(MethodError::CodecError));
+    assert_eq!(result.map_err(|e| e.code()), Err(ErrorCode::Codec));
 
     join.join().unwrap();
 }
@@ -151,7 +169,1274 @@ fn bad_rpc_response_meta() {
     let stub = EchoStub::new(&channel);
 
     let result= core.run(stub.echo(msg));
-    assert_eq!(result, Err(MethodError::UnknownError));
+    assert_eq!(result.map_err(|e| e.code()), Err(ErrorCode::Codec));
 
     join.join().unwrap();
 }
+
+#[test]
+fn request_deadline_exceeded() {
+    let addr = "127.0.0.1:9004";
+    let mut core = Core::new().unwrap();
+
+    let mut builder = MockServerBuilder::new(addr, core.handle());
+
+    let msg = simple(10, true, "HelloWorld");
+
+    let send_msg = msg.clone();
+    builder.respond_package(
+        move || {
+            let meta = RpcResponseMeta::new();
+            let ctrl = Controller::default();
+            (meta, ctrl, encode_message(&send_msg).freeze())
+        },
+        Duration::from_secs(1),
+    );
+
+    let join = spawn(move || {
+        builder.build().start().unwrap();
+    });
+
+    let builder = ChannelBuilder::single_server(addr, core.handle())
+        .deadline(Some(Duration::from_millis(200)))
+        .max_concurrency(1);
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    let result = core.run(stub.echo(msg));
+    assert_eq!(result.map_err(|e| e.code()), Err(ErrorCode::Timeout));
+    assert!(!channel.congested());
+
+    join.join().unwrap();
+}
+
+#[test]
+fn server_list_partial_connect() {
+    let addr = "127.0.0.1:9005";
+    // nothing is listening on this one
+    let dead_addr = "127.0.0.1:9006";
+    let mut core = Core::new().unwrap();
+
+    let mut builder = MockServerBuilder::new(addr, core.handle());
+
+    let msg = simple(10, true, "HelloWorld");
+
+    let send_msg = msg.clone();
+    builder.respond_package(
+        move || {
+            let meta = RpcResponseMeta::new();
+            let ctrl = Controller::default();
+            (meta, ctrl, encode_message(&send_msg).freeze())
+        },
+        Duration::from_secs(0),
+    );
+
+    let join = spawn(move || {
+        builder.build().start().unwrap();
+    });
+
+    let url = format!("list://{},{}", addr, dead_addr);
+    let builder = ChannelBuilder::from_url(&url, core.handle());
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    let (resp, _info) = core.run(stub.echo(msg.clone())).unwrap();
+    assert_eq!(resp, msg);
+
+    join.join().unwrap();
+}
+
+#[test]
+fn round_robin_server_list() {
+    let addrs = ["127.0.0.1:9007", "127.0.0.1:9008"];
+    let mut core = Core::new().unwrap();
+
+    let msg = simple(10, true, "HelloWorld");
+
+    // each server answers exactly one request
+    let joins: Vec<_> = addrs
+        .iter()
+        .map(|addr| {
+            let mut builder = MockServerBuilder::new(addr, core.handle());
+            let send_msg = msg.clone();
+            builder.respond_package(
+                move || {
+                    let meta = RpcResponseMeta::new();
+                    let ctrl = Controller::default();
+                    (meta, ctrl, encode_message(&send_msg).freeze())
+                },
+                Duration::from_secs(0),
+            );
+            spawn(move || {
+                builder.build().start().unwrap();
+            })
+        })
+        .collect();
+
+    let builder = ChannelBuilder::server_list(&addrs, core.handle()).load_balancer_name("rr");
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    for _ in 0..addrs.len() {
+        let (resp, _info) = core.run(stub.echo(msg.clone())).unwrap();
+        assert_eq!(resp, msg);
+    }
+
+    for join in joins {
+        join.join().unwrap();
+    }
+}
+
+#[test]
+fn consistent_hash_same_request_code() {
+    let addrs = ["127.0.0.1:9009", "127.0.0.1:9010"];
+    let mut core = Core::new().unwrap();
+
+    let rounds = 3;
+    // every server tags its response with its address
+    let joins: Vec<_> = addrs
+        .iter()
+        .map(|addr| {
+            let mut builder = MockServerBuilder::new(addr, core.handle());
+            for _ in 0..rounds {
+                let send_msg = simple(10, true, addr);
+                builder.respond_package(
+                    move || {
+                        let meta = RpcResponseMeta::new();
+                        let ctrl = Controller::default();
+                        (meta, ctrl, encode_message(&send_msg).freeze())
+                    },
+                    Duration::from_secs(0),
+                );
+            }
+            spawn(move || {
+                builder.build().start().unwrap();
+            })
+        })
+        .collect();
+
+    let builder =
+        ChannelBuilder::server_list(&addrs, core.handle()).load_balancer_name("c_murmurhash");
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    let options = CallOptions::new().request_code(42);
+    let tags: Vec<_> = (0..rounds)
+        .map(|_| {
+            let fut = stub.echo_with_options(simple(10, true, ""), options.clone());
+            let (resp, _info) = core.run(fut).unwrap();
+            resp.get_str_val().to_string()
+        })
+        .collect();
+    assert!(tags.iter().all(|tag| *tag == tags[0]));
+
+    // the chosen server is done and closes its connection, then the
+    // requests fall over to the other one until it is done as well
+    let other = addrs.iter().find(|addr| **addr != tags[0]).unwrap();
+    let mut answered = 0;
+    for _ in 0..rounds * 2 {
+        let fut = stub.echo_with_options(simple(10, true, ""), options.clone());
+        if let Ok((resp, _info)) = core.run(fut) {
+            assert_eq!(resp.get_str_val(), *other);
+            answered += 1;
+        }
+    }
+    assert_eq!(answered, rounds);
+
+    for join in joins {
+        join.join().unwrap();
+    }
+}
+
+#[test]
+fn file_naming_service_update() {
+    let addrs = ["127.0.0.1:9011", "127.0.0.1:9012"];
+    let path = env::temp_dir().join("copra_file_naming_service_update");
+    let mut core = Core::new().unwrap();
+
+    // each server answers exactly one request, tagged with its address
+    let joins: Vec<_> = addrs
+        .iter()
+        .map(|addr| {
+            let mut builder = MockServerBuilder::new(addr, core.handle());
+            let send_msg = simple(10, true, addr);
+            builder.respond_package(
+                move || {
+                    let meta = RpcResponseMeta::new();
+                    let ctrl = Controller::default();
+                    (meta, ctrl, encode_message(&send_msg).freeze())
+                },
+                Duration::from_secs(0),
+            );
+            spawn(move || {
+                builder.build().start().unwrap();
+            })
+        })
+        .collect();
+
+    fs::write(&path, format!("# servers\n{}\n", addrs[0])).unwrap();
+    let naming = FileNamingService::new(&path).interval(Duration::from_millis(200));
+    let channel = core.run(ChannelBuilder::naming_service(naming, core.handle()).build())
+        .unwrap();
+    let stub = EchoStub::new(&channel);
+
+    let (resp, _info) = core.run(stub.echo(simple(10, true, ""))).unwrap();
+    assert_eq!(resp.get_str_val(), addrs[0]);
+
+    // replace the server, and wait for the channel to notice
+    fs::write(&path, format!("{}\n", addrs[1])).unwrap();
+    core.run(Timer::default().sleep(Duration::from_millis(600)))
+        .unwrap();
+
+    let (resp, _info) = core.run(stub.echo(simple(10, true, ""))).unwrap();
+    assert_eq!(resp.get_str_val(), addrs[1]);
+
+    for join in joins {
+        join.join().unwrap();
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+/// A resolver that gives whatever addresses are set
+#[derive(Clone)]
+struct FakeResolver(Arc<Mutex<Vec<SocketAddr>>>);
+
+impl Resolve for FakeResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        assert_eq!(host, "rpc.example.com");
+        let addrs = self.0.lock().unwrap();
+        Ok(addrs.iter().map(|addr| SocketAddr::new(addr.ip(), port)).collect())
+    }
+}
+
+#[test]
+fn dns_naming_service_update() {
+    let addrs = ["127.0.0.1:9013", "127.0.0.2:9013"];
+    let mut core = Core::new().unwrap();
+
+    // each server answers exactly one request, tagged with its address
+    let joins: Vec<_> = addrs
+        .iter()
+        .map(|addr| {
+            let mut builder = MockServerBuilder::new(addr, core.handle());
+            let send_msg = simple(10, true, addr);
+            builder.respond_package(
+                move || {
+                    let meta = RpcResponseMeta::new();
+                    let ctrl = Controller::default();
+                    (meta, ctrl, encode_message(&send_msg).freeze())
+                },
+                Duration::from_secs(0),
+            );
+            spawn(move || {
+                builder.build().start().unwrap();
+            })
+        })
+        .collect();
+
+    let resolved = Arc::new(Mutex::new(vec![addrs[0].parse().unwrap()]));
+    let naming = DnsNamingService::new("rpc.example.com", 9013)
+        .interval(Duration::from_millis(200))
+        .resolver(FakeResolver(resolved.clone()));
+    let channel = core.run(ChannelBuilder::naming_service(naming, core.handle()).build())
+        .unwrap();
+    let stub = EchoStub::new(&channel);
+
+    let (resp, _info) = core.run(stub.echo(simple(10, true, ""))).unwrap();
+    assert_eq!(resp.get_str_val(), addrs[0]);
+
+    // the host moves to another address, wait for the channel to notice
+    *resolved.lock().unwrap() = vec![addrs[1].parse().unwrap()];
+    core.run(Timer::default().sleep(Duration::from_millis(600)))
+        .unwrap();
+
+    let (resp, _info) = core.run(stub.echo(simple(10, true, ""))).unwrap();
+    assert_eq!(resp.get_str_val(), addrs[1]);
+
+    for join in joins {
+        join.join().unwrap();
+    }
+}
+
+/// Gives the first address, then an error, then the second address
+struct FlakyNaming(&'static str, &'static str);
+
+impl NamingService for FlakyNaming {
+    fn watch(&self, _handle: &Handle) -> ServerListStream {
+        let first = ServerNode::new(self.0.parse().unwrap());
+        let second = ServerNode::new(self.1.parse().unwrap());
+        let error = io::Error::new(io::ErrorKind::Other, "lookup failed");
+        Box::new(stream::iter_result(vec![Ok(vec![first]), Err(error), Ok(vec![second])]))
+    }
+}
+
+#[test]
+fn naming_update_after_error() {
+    let addrs = ["127.0.0.1:9029", "127.0.0.1:9030"];
+    start_server(addrs[0], Reject);
+    start_echo_server(addrs[1]);
+    let mut core = Core::new().unwrap();
+
+    let naming = FlakyNaming(addrs[0], addrs[1]);
+    let builder = ChannelBuilder::naming_service(naming, core.handle()).max_retry(0);
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    // nothing but the naming stream wakes the channel up here
+    core.run(Timer::default().sleep(Duration::from_millis(300)))
+        .unwrap();
+
+    let (resp, _info) = core.run(stub.echo(simple(10, true, "moved"))).unwrap();
+    assert_eq!(resp.get_str_val(), "moved");
+}
+
+#[test]
+fn eject_and_recover_server() {
+    let addr = "127.0.0.1:9015";
+    let mut core = Core::new().unwrap();
+
+    let mut builder = MockServerBuilder::new(addr, core.handle());
+
+    let msg = simple(10, true, "HelloWorld");
+
+    let send_msg = msg.clone();
+    builder.respond_package(
+        move || {
+            let meta = RpcResponseMeta::new();
+            let ctrl = Controller::default();
+            (meta, ctrl, encode_message(&send_msg).freeze())
+        },
+        Duration::from_secs(0),
+    );
+    // the first request breaks the connection
+    builder.close_connection();
+
+    let join = spawn(move || {
+        builder.build().start().unwrap();
+    });
+
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let listener_changes = changes.clone();
+    let builder = ChannelBuilder::single_server(addr, core.handle())
+        .max_retry(0)
+        .health_check_backoff(Duration::from_millis(200), Duration::from_secs(1))
+        .health_listener(move |addr, health| {
+            listener_changes.lock().unwrap().push((addr, health));
+        });
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    let result = core.run(stub.echo(msg.clone()));
+    assert_eq!(result.map_err(|e| e.code()), Err(ErrorCode::ConnectionFailed));
+
+    // wait for the server to be checked and put back
+    core.run(Timer::default().sleep(Duration::from_millis(600)))
+        .unwrap();
+
+    let (resp, _info) = core.run(stub.echo(msg.clone())).unwrap();
+    assert_eq!(resp, msg);
+
+    let addr = addr.parse().unwrap();
+    assert_eq!(
+        *changes.lock().unwrap(),
+        vec![(addr, ServerHealth::Unhealthy), (addr, ServerHealth::Healthy)]
+    );
+
+    join.join().unwrap();
+}
+
+#[test]
+fn circuit_breaker_isolates_failing_server() {
+    let addr = "127.0.0.1:9016";
+    let mut core = Core::new().unwrap();
+
+    let mut builder = MockServerBuilder::new(addr, core.handle());
+
+    let msg = simple(10, true, "HelloWorld");
+
+    let send_msg = msg.clone();
+    builder.respond_package(
+        move || {
+            let meta = RpcResponseMeta::new();
+            let ctrl = Controller::default();
+            (meta, ctrl, encode_message(&send_msg).freeze())
+        },
+        Duration::from_secs(0),
+    );
+    for _ in 0..2 {
+        builder.respond_package(
+            || {
+                let mut meta = RpcResponseMeta::new();
+                meta.set_error_code(1);
+                let ctrl = Controller::default();
+                (meta, ctrl, Bytes::new())
+            },
+            Duration::from_secs(0),
+        );
+    }
+
+    let join = spawn(move || {
+        builder.build().start().unwrap();
+    });
+
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let listener_changes = changes.clone();
+    let breaker = CircuitBreaker::new()
+        .window(2, 2)
+        .cooldown(Duration::from_millis(300));
+    let builder = ChannelBuilder::single_server(addr, core.handle())
+        .max_retry(0)
+        .circuit_breaker(breaker)
+        .health_listener(move |_, health| {
+            listener_changes.lock().unwrap().push(health);
+        });
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    for _ in 0..2 {
+        let result = core.run(stub.echo(msg.clone()));
+        assert_eq!(result.map_err(|e| e.code()), Err(ErrorCode::Unknown));
+    }
+    // the breaker is open, the request does not reach the server
+    assert!(core.run(stub.echo(msg.clone())).is_err());
+    assert_eq!(*changes.lock().unwrap(), vec![ServerHealth::Unhealthy]);
+
+    // wait for the breaker to be half-open
+    core.run(Timer::default().sleep(Duration::from_millis(600)))
+        .unwrap();
+
+    let (resp, _info) = core.run(stub.echo(msg.clone())).unwrap();
+    assert_eq!(resp, msg);
+    assert_eq!(
+        *changes.lock().unwrap(),
+        vec![ServerHealth::Unhealthy, ServerHealth::Healthy]
+    );
+
+    join.join().unwrap();
+}
+
+#[derive(Clone)]
+struct Echo;
+
+impl EchoService for Echo {
+    type EchoFuture = Box<Future<Item = (Simple, Controller), Error = MethodError>>;
+
+    fn echo(&self, msg: (Simple, Controller)) -> Self::EchoFuture {
+        Box::new(future::ok(msg))
+    }
+}
+
+#[derive(Clone)]
+struct Reject;
+
+impl EchoService for Reject {
+    type EchoFuture = Box<Future<Item = (Simple, Controller), Error = MethodError>>;
+
+    fn echo(&self, (msg, _): (Simple, Controller)) -> Self::EchoFuture {
+        let text = format!("{} is not welcome", msg.get_str_val());
+        Box::new(future::err(MethodError::application(42, text)))
+    }
+}
+
+/// Start a real server, which can take any number of connections.
+fn start_echo_server(addr: &'static str) {
+    start_server(addr, Echo)
+}
+
+fn start_server<S>(addr: &'static str, service: S)
+where
+    S: EchoService + Clone + Send + Sync + 'static,
+{
+    let mut registry = ServiceRegistry::new();
+    registry.register_service(EchoRegistrant::new(service));
+    spawn(move || {
+        let server = ServerBuilder::new(addr, registry).build().unwrap();
+        server.start();
+    });
+    while TcpStream::connect(addr).is_err() {
+        sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn pooled_and_short_connections() {
+    let addr = "127.0.0.1:9017";
+    start_echo_server(addr);
+    let mut core = Core::new().unwrap();
+
+    for &connection_type in &[ConnectionType::Pooled(3), ConnectionType::Short] {
+        let builder = ChannelBuilder::single_server(addr, core.handle())
+            .connection_type(connection_type);
+        let channel = core.run(builder.build()).unwrap();
+        let stub = EchoStub::new(&channel);
+
+        let requests: Vec<_> = (0..10)
+            .map(|i| stub.echo(simple(i, true, "HelloWorld")))
+            .collect();
+        let responses = core.run(future::join_all(requests)).unwrap();
+        for (i, (resp, _info)) in responses.into_iter().enumerate() {
+            assert_eq!(resp, simple(i as i32, true, "HelloWorld"));
+        }
+    }
+}
+
+/// Start a server that closes the connection once a request comes in, and
+/// counts such requests.
+fn start_closing_server(addr: &'static str) -> Arc<AtomicUsize> {
+    let listener = TcpListener::bind(addr).unwrap();
+    let received = Arc::new(AtomicUsize::new(0));
+    let counter = received.clone();
+    spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let counter = counter.clone();
+            spawn(move || {
+                let mut chunk = [0; 1024];
+                if let Ok(n) = stream.read(&mut chunk) {
+                    if n > 0 {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            });
+        }
+    });
+    received
+}
+
+/// Echo the message, and count the requests
+#[derive(Clone)]
+struct Counted(Arc<AtomicUsize>);
+
+impl EchoService for Counted {
+    type EchoFuture = Box<Future<Item = (Simple, Controller), Error = MethodError>>;
+
+    fn echo(&self, msg: (Simple, Controller)) -> Self::EchoFuture {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Box::new(future::ok(msg))
+    }
+}
+
+#[test]
+fn retry_on_another_server() {
+    let addrs = ["127.0.0.1:9033", "127.0.0.1:9034"];
+    let closed = start_closing_server(addrs[0]);
+    let answered = Arc::new(AtomicUsize::new(0));
+    start_server(addrs[1], Counted(answered.clone()));
+    let mut core = Core::new().unwrap();
+
+    // the first request goes to the closing server
+    let builder = ChannelBuilder::server_list(&addrs, core.handle()).load_balancer_name("rr");
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    let msg = simple(10, true, "HelloWorld");
+    let (resp, _info) = core.run(stub.echo(msg.clone())).unwrap();
+    assert_eq!(resp, msg);
+    assert_eq!(closed.load(Ordering::SeqCst), 1);
+    assert_eq!(answered.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn no_retry_with_zero_max_retry() {
+    let addrs = ["127.0.0.1:9035", "127.0.0.1:9036"];
+    let closed = start_closing_server(addrs[0]);
+    let answered = Arc::new(AtomicUsize::new(0));
+    start_server(addrs[1], Counted(answered.clone()));
+    let mut core = Core::new().unwrap();
+
+    let builder = ChannelBuilder::server_list(&addrs, core.handle())
+        .load_balancer_name("rr")
+        .max_retry(0);
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    let result = core.run(stub.echo(simple(10, true, "HelloWorld")));
+    assert_eq!(result.map_err(|e| e.code()), Err(ErrorCode::ConnectionFailed));
+    assert_eq!(closed.load(Ordering::SeqCst), 1);
+    assert_eq!(answered.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn reconnect_broken_connection() {
+    let addr = "127.0.0.1:9018";
+    let mut core = Core::new().unwrap();
+
+    let mut builder = MockServerBuilder::new(addr, core.handle());
+
+    let msg = simple(10, true, "HelloWorld");
+
+    let send_msg = msg.clone();
+    builder.respond_package(
+        move || {
+            let meta = RpcResponseMeta::new();
+            let ctrl = Controller::default();
+            (meta, ctrl, encode_message(&send_msg).freeze())
+        },
+        Duration::from_secs(0),
+    );
+    // the first request breaks the connection
+    builder.close_connection();
+
+    let join = spawn(move || {
+        builder.build().start().unwrap();
+    });
+
+    let policy = ReconnectPolicy::new()
+        .initial_delay(Duration::from_millis(200))
+        .max_attempts(3);
+    let builder = ChannelBuilder::single_server(addr, core.handle())
+        .max_retry(0)
+        .reconnect_policy(policy);
+    let channel = core.run(builder.build()).unwrap();
+    let watch = channel.connection_watch();
+    let changes = watch.changes();
+    let stub = EchoStub::new(&channel);
+    let addr = addr.parse().unwrap();
+    assert_eq!(watch.state(&addr), Some(ConnectionState::Connected));
+
+    let result = core.run(stub.echo(msg.clone()));
+    assert_eq!(result.map_err(|e| e.code()), Err(ErrorCode::ConnectionFailed));
+    assert_eq!(watch.state(&addr), Some(ConnectionState::Reconnecting(1)));
+    assert!(!watch.is_connected());
+
+    // fail at once while reconnecting
+    let result = core.run(stub.echo(msg.clone()));
+    assert_eq!(result.map_err(|e| e.code()), Err(ErrorCode::ConnectionFailed));
+
+    core.run(Timer::default().sleep(Duration::from_millis(600)))
+        .unwrap();
+    assert_eq!(watch.state(&addr), Some(ConnectionState::Connected));
+
+    let (resp, _info) = core.run(stub.echo(msg.clone())).unwrap();
+    assert_eq!(resp, msg);
+
+    let changes = core.run(changes.take(2).collect()).unwrap();
+    assert_eq!(
+        changes,
+        vec![
+            (addr, ConnectionState::Reconnecting(1)),
+            (addr, ConnectionState::Connected),
+        ]
+    );
+
+    join.join().unwrap();
+}
+
+#[test]
+fn remote_error_code_and_text() {
+    let addr = "127.0.0.1:9019";
+    start_echo_server(addr);
+    let mut core = Core::new().unwrap();
+
+    let builder = ChannelBuilder::single_server(addr, core.handle());
+    let channel = core.run(builder.build()).unwrap();
+    let wrapper = RpcWrapper::new(ProtobufCodec::<Simple, Simple>::new(), &channel);
+
+    let msg = simple(10, true, "HelloWorld");
+    let bundle = (msg, "Echo".to_string(), "missing".to_string());
+    let error = core.run(wrapper.call(bundle, CallOptions::default()))
+        .unwrap_err();
+    assert_eq!(error.code(), ErrorCode::NoSuchMethod);
+    assert_eq!(error.text(), "Echo::missing is not found");
+}
+
+#[test]
+fn application_error_from_handler() {
+    let addr = "127.0.0.1:9020";
+    start_server(addr, Reject);
+    let mut core = Core::new().unwrap();
+
+    let builder = ChannelBuilder::single_server(addr, core.handle());
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    let error = core.run(stub.echo(simple(10, true, "HelloWorld")))
+        .unwrap_err();
+    assert_eq!(error, MethodError::application(42, "HelloWorld is not welcome"));
+    assert_eq!(error.code(), ErrorCode::Application(42));
+}
+
+/// Fails with the code in `int_val`, made by `MethodError::application` if
+/// `bool_val` is set
+#[derive(Clone)]
+struct RejectWith;
+
+impl EchoService for RejectWith {
+    type EchoFuture = Box<Future<Item = (Simple, Controller), Error = MethodError>>;
+
+    fn echo(&self, (msg, _): (Simple, Controller)) -> Self::EchoFuture {
+        let code = msg.get_int_val();
+        let error = if msg.get_bool_val() {
+            MethodError::application(code, "rejected")
+        } else {
+            MethodError::new(ErrorCode::Application(code), "rejected")
+        };
+        Box::new(future::err(error))
+    }
+}
+
+#[test]
+fn reserved_application_error_code() {
+    let addr = "127.0.0.1:9031";
+    start_server(addr, RejectWith);
+    let mut core = Core::new().unwrap();
+
+    let builder = ChannelBuilder::single_server(addr, core.handle());
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    for &by_application in &[true, false] {
+        // reserved codes must not be read as success or as another failure
+        for &code in &[0, 1, 1003, 1999] {
+            let error = core.run(stub.echo(simple(code, by_application, "")))
+                .unwrap_err();
+            assert_eq!(error.code(), ErrorCode::Unknown);
+            assert_eq!(error.text(), "rejected");
+        }
+        for &code in &[-1, 999, 2000] {
+            let error = core.run(stub.echo(simple(code, by_application, "")))
+                .unwrap_err();
+            assert_eq!(error.code(), ErrorCode::Application(code));
+        }
+    }
+}
+
+#[test]
+fn missing_service_and_method() {
+    let addr = "127.0.0.1:9021";
+    let failures = Arc::new(DispatchFailures::new());
+    let mut registry = ServiceRegistry::new();
+    registry.register_service(EchoRegistrant::new(Echo));
+    let server_failures = failures.clone();
+    spawn(move || {
+        let server = ServerBuilder::new(addr, registry)
+            .dispatch_failures(server_failures)
+            .build()
+            .unwrap();
+        server.start();
+    });
+    while TcpStream::connect(addr).is_err() {
+        sleep(Duration::from_millis(10));
+    }
+    let mut core = Core::new().unwrap();
+
+    let builder = ChannelBuilder::single_server(addr, core.handle());
+    let channel = core.run(builder.build()).unwrap();
+    let wrapper = RpcWrapper::new(ProtobufCodec::<Simple, Simple>::new(), &channel);
+
+    let msg = simple(10, true, "HelloWorld");
+    let bundle = (msg.clone(), "Ecoh".to_string(), "echo".to_string());
+    let error = core.run(wrapper.call(bundle, CallOptions::default()))
+        .unwrap_err();
+    assert_eq!(error.code(), ErrorCode::NoSuchService);
+    assert_eq!(error.text(), "Ecoh is not found");
+
+    let bundle = (msg, "Echo".to_string(), "ecoh".to_string());
+    let error = core.run(wrapper.call(bundle, CallOptions::default()))
+        .unwrap_err();
+    assert_eq!(error.code(), ErrorCode::NoSuchMethod);
+
+    // over http
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /Echo/ecoh HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+        .unwrap();
+    let mut response = [0; 24];
+    stream.read_exact(&mut response).unwrap();
+    assert_eq!(&response[..], &b"HTTP/1.1 404 Not Found\r\n"[..]);
+
+    assert_eq!(failures.no_such_service(), 1);
+    assert_eq!(failures.no_such_method(), 2);
+}
+
+/// A handler that never finishes, and tells when it is dropped
+struct Hang(Arc<AtomicBool>);
+
+impl Future for Hang {
+    type Item = (Simple, Controller);
+    type Error = MethodError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for Hang {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone)]
+struct Deadline(Arc<AtomicBool>);
+
+impl EchoService for Deadline {
+    type EchoFuture = Box<Future<Item = (Simple, Controller), Error = MethodError>>;
+
+    fn echo(&self, (mut msg, controller): (Simple, Controller)) -> Self::EchoFuture {
+        if msg.get_bool_val() {
+            return Box::new(Hang(self.0.clone()));
+        }
+        // tell the client how much time is left
+        let remaining = controller.remaining().unwrap();
+        let remaining_ms = remaining.as_secs() * 1000 + u64::from(remaining.subsec_millis());
+        msg.set_int_val(remaining_ms as i32);
+        Box::new(future::ok((msg, controller)))
+    }
+}
+
+#[test]
+fn deadline_sent_to_server() {
+    let addr = "127.0.0.1:9022";
+    let dropped = Arc::new(AtomicBool::new(false));
+    start_server(addr, Deadline(dropped.clone()));
+    let mut core = Core::new().unwrap();
+
+    let builder = ChannelBuilder::single_server(addr, core.handle())
+        .deadline(Some(Duration::from_millis(500)))
+        .max_retry(0);
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    let (resp, _info) = core.run(stub.echo(simple(0, false, "HelloWorld")))
+        .unwrap();
+    assert!(resp.get_int_val() > 0 && resp.get_int_val() <= 500);
+
+    // the timeout of the call is sent instead of the one of the channel
+    let options = CallOptions::new().timeout(Duration::from_millis(200));
+    let (resp, _info) = core.run(stub.echo_with_options(simple(0, false, "HelloWorld"), options))
+        .unwrap();
+    assert!(resp.get_int_val() > 0 && resp.get_int_val() <= 200);
+
+    let options = CallOptions::new().timeout(Duration::from_millis(200));
+    let result = core.run(stub.echo_with_options(simple(0, true, "HelloWorld"), options));
+    assert_eq!(result.map_err(|e| e.code()), Err(ErrorCode::Timeout));
+
+    // the server gives up the handler as well
+    core.run(Timer::default().sleep(Duration::from_millis(400)))
+        .unwrap();
+    assert!(dropped.load(Ordering::SeqCst));
+}
+
+/// Tell the client the log id of the request
+#[derive(Clone)]
+struct LogId;
+
+impl EchoService for LogId {
+    type EchoFuture = Box<Future<Item = (Simple, Controller), Error = MethodError>>;
+
+    fn echo(&self, (mut msg, controller): (Simple, Controller)) -> Self::EchoFuture {
+        msg.set_int_val(controller.log_id().unwrap_or(-1) as i32);
+        Box::new(future::ok((msg, controller)))
+    }
+}
+
+#[test]
+fn log_id_sent_to_server() {
+    let addr = "127.0.0.1:9037";
+    start_server(addr, LogId);
+    let mut core = Core::new().unwrap();
+
+    let builder = ChannelBuilder::single_server(addr, core.handle());
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    let options = CallOptions::new().log_id(42);
+    let (resp, _info) = core.run(stub.echo_with_options(simple(0, true, ""), options))
+        .unwrap();
+    assert_eq!(resp.get_int_val(), 42);
+
+    let (resp, _info) = core.run(stub.echo(simple(0, true, ""))).unwrap();
+    assert_eq!(resp.get_int_val(), -1);
+}
+
+#[test]
+fn max_retry_of_call() {
+    let addrs = ["127.0.0.1:9038", "127.0.0.1:9039"];
+    let closed = start_closing_server(addrs[0]);
+    start_echo_server(addrs[1]);
+    let mut core = Core::new().unwrap();
+
+    // the first request goes to the closing server
+    let builder = ChannelBuilder::server_list(&addrs, core.handle())
+        .load_balancer_name("rr")
+        .max_retry(0);
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    let msg = simple(10, true, "HelloWorld");
+    let options = CallOptions::new().max_retry(1);
+    let (resp, _info) = core.run(stub.echo_with_options(msg.clone(), options))
+        .unwrap();
+    assert_eq!(resp, msg);
+    assert_eq!(closed.load(Ordering::SeqCst), 1);
+}
+
+#[derive(Clone)]
+struct Slow(Timer);
+
+impl EchoService for Slow {
+    type EchoFuture = Box<Future<Item = (Simple, Controller), Error = MethodError>>;
+
+    fn echo(&self, msg: (Simple, Controller)) -> Self::EchoFuture {
+        let fut = self.0
+            .sleep(Duration::from_millis(300))
+            .then(move |_| Ok(msg));
+        Box::new(fut)
+    }
+}
+
+/// Answers the first request late and the others at once, with the order of
+/// the request in `int_val`
+#[derive(Clone)]
+struct SlowFirst(Timer, Arc<AtomicUsize>);
+
+impl EchoService for SlowFirst {
+    type EchoFuture = Box<Future<Item = (Simple, Controller), Error = MethodError>>;
+
+    fn echo(&self, (mut msg, controller): (Simple, Controller)) -> Self::EchoFuture {
+        let nth = self.1.fetch_add(1, Ordering::SeqCst);
+        msg.set_int_val(nth as i32);
+        let delay = if nth == 0 { 600 } else { 0 };
+        let fut = self.0
+            .sleep(Duration::from_millis(delay))
+            .then(move |_| Ok((msg, controller)));
+        Box::new(fut)
+    }
+}
+
+#[test]
+fn backup_request_answered_first() {
+    let addr = "127.0.0.1:9032";
+    let received = Arc::new(AtomicUsize::new(0));
+    start_server(addr, SlowFirst(Timer::default(), received.clone()));
+    let mut core = Core::new().unwrap();
+
+    let builder = ChannelBuilder::single_server(addr, core.handle())
+        .backup_request(Duration::from_millis(200));
+    let channel = core.run(builder.build()).unwrap();
+    let mut changes = channel.connection_watch().changes();
+    let stub = EchoStub::new(&channel);
+
+    // the backup request is answered before the first one
+    let start = Instant::now();
+    let (resp, _info) = core.run(stub.echo(simple(0, true, ""))).unwrap();
+    assert_eq!(resp.get_int_val(), 1);
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert_eq!(received.load(Ordering::SeqCst), 2);
+
+    // the late response is dropped, and the channel keeps working
+    core.run(Timer::default().sleep(Duration::from_millis(700)))
+        .unwrap();
+    let (resp, _info) = core.run(stub.echo(simple(0, true, ""))).unwrap();
+    assert_eq!(resp.get_int_val(), 2);
+    assert_eq!(received.load(Ordering::SeqCst), 3);
+    let change = core.run(future::lazy(|| changes.poll())).unwrap();
+    assert_eq!(change, Async::NotReady);
+}
+
+#[test]
+fn graceful_shutdown() {
+    let addr = "127.0.0.1:9023";
+    let mut registry = ServiceRegistry::new();
+    registry.register_service(EchoRegistrant::new(Slow(Timer::default())));
+    let server = ServerBuilder::new(addr, registry).build().unwrap();
+    let handle = server.handle();
+    let join = spawn(move || server.start());
+    while TcpStream::connect(addr).is_err() {
+        sleep(Duration::from_millis(10));
+    }
+    let mut core = Core::new().unwrap();
+
+    let builder = ChannelBuilder::single_server(addr, core.handle());
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    let start = Instant::now();
+    let shutdown = spawn(move || {
+        sleep(Duration::from_millis(100));
+        handle.shutdown(Duration::from_secs(5));
+    });
+
+    // the request in flight is answered
+    let msg = simple(10, true, "HelloWorld");
+    let (resp, _info) = core.run(stub.echo(msg.clone())).unwrap();
+    assert_eq!(resp, msg);
+
+    // and the idle connection is closed without waiting for the grace period
+    shutdown.join().unwrap();
+    join.join().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn shutdown_before_start() {
+    let addr = "127.0.0.1:9040";
+    let mut registry = ServiceRegistry::new();
+    registry.register_service(EchoRegistrant::new(Echo));
+    let server = ServerBuilder::new(addr, registry).build().unwrap();
+    server.handle().shutdown(Duration::from_secs(5));
+
+    // the port is taken, the server would fail if it tried to listen
+    let _taken = TcpListener::bind(addr).unwrap();
+    let (stopped, wait) = mpsc::channel();
+    spawn(move || {
+        server.start();
+        stopped.send(()).unwrap();
+    });
+    wait.recv_timeout(Duration::from_secs(1)).unwrap();
+}
+
+#[test]
+fn reject_requests_over_limit() {
+    let addr = "127.0.0.1:9024";
+    let failures = Arc::new(DispatchFailures::new());
+    let mut registry = ServiceRegistry::new();
+    registry.register_service(EchoRegistrant::new(Slow(Timer::default())));
+    registry.max_concurrency("Echo", "echo", 1);
+    let server_failures = failures.clone();
+    spawn(move || {
+        let server = ServerBuilder::new(addr, registry)
+            .max_concurrency(10)
+            .dispatch_failures(server_failures)
+            .build()
+            .unwrap();
+        server.start();
+    });
+    while TcpStream::connect(addr).is_err() {
+        sleep(Duration::from_millis(10));
+    }
+    let mut core = Core::new().unwrap();
+
+    let builder = ChannelBuilder::single_server(addr, core.handle()).max_retry(0);
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    // an http request takes the only slot
+    let request = b"GET /Echo/echo HTTP/1.1\r\nContent-Length: 0\r\n\r\n";
+    let mut first = TcpStream::connect(addr).unwrap();
+    first.write_all(request).unwrap();
+    sleep(Duration::from_millis(100));
+
+    let mut second = TcpStream::connect(addr).unwrap();
+    second.write_all(request).unwrap();
+    let mut response = [0; 32];
+    second.read_exact(&mut response).unwrap();
+    assert_eq!(&response[..], &b"HTTP/1.1 503 Service Unavailable"[..]);
+
+    let error = core.run(stub.echo(simple(10, true, "HelloWorld")))
+        .unwrap_err();
+    assert_eq!(error.code(), ErrorCode::Overloaded);
+    assert_eq!(error.text(), "Echo::echo is overloaded");
+
+    let mut response = [0; 15];
+    first.read_exact(&mut response).unwrap();
+    assert_eq!(&response[..], &b"HTTP/1.1 200 OK"[..]);
+
+    // the slot is freed once the request is answered
+    let msg = simple(10, true, "HelloWorld");
+    let calls = vec![stub.echo(msg.clone()), stub.echo(msg.clone())];
+    let results = core.run(future::join_all(
+        calls.into_iter().map(|call| call.then(Ok::<_, ()>)),
+    )).unwrap();
+    assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+
+    assert_eq!(failures.overloaded(), 3);
+}
+
+#[test]
+fn auto_concurrency_limit() {
+    let addr = "127.0.0.1:9025";
+    let mut registry = ServiceRegistry::new();
+    registry.register_service(EchoRegistrant::new(Slow(Timer::default())));
+    spawn(move || {
+        let server = ServerBuilder::new(addr, registry)
+            .auto_concurrency(AutoConcurrency::new().initial(2))
+            .build()
+            .unwrap();
+        server.start();
+    });
+    while TcpStream::connect(addr).is_err() {
+        sleep(Duration::from_millis(10));
+    }
+    let mut core = Core::new().unwrap();
+
+    let builder = ChannelBuilder::single_server(addr, core.handle()).max_retry(0);
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    // the limit is not adjusted before a window is sampled
+    let msg = simple(10, true, "HelloWorld");
+    let calls: Vec<_> = (0..5).map(|_| stub.echo(msg.clone())).collect();
+    let results = core.run(future::join_all(
+        calls.into_iter().map(|call| call.then(Ok::<_, ()>)),
+    )).unwrap();
+    let overloaded = results
+        .iter()
+        .filter(|result| match **result {
+            Err(ref e) => e.code() == ErrorCode::Overloaded,
+            Ok(_) => false,
+        })
+        .count();
+    assert_eq!(overloaded, 3);
+}
+
+#[test]
+fn rate_limited_channel() {
+    let addr = "127.0.0.1:9026";
+    start_echo_server(addr);
+    let mut core = Core::new().unwrap();
+    let msg = simple(10, true, "HelloWorld");
+
+    let builder = ChannelBuilder::single_server(addr, core.handle())
+        .rate_limit(RateLimit::new(10).burst(1));
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+    core.run(stub.echo(msg.clone())).unwrap();
+    // the budget is shared with the clones
+    let cloned = channel.clone();
+    let error = core.run(EchoStub::new(&cloned).echo(msg.clone()))
+        .unwrap_err();
+    assert_eq!(error.code(), ErrorCode::Overloaded);
+
+    let limit = RateLimit::new(10).burst(1).throttle(Throttle::Delay);
+    let builder = ChannelBuilder::single_server(addr, core.handle()).rate_limit(limit);
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+    let start = Instant::now();
+    let calls: Vec<_> = (0..4).map(|_| stub.echo(msg.clone())).collect();
+    let results = core.run(future::join_all(calls)).unwrap();
+    assert!(results.into_iter().all(|(resp, _)| resp == msg));
+    // a token every 100ms, give or take a tick of the timer
+    assert!(start.elapsed() >= Duration::from_millis(280));
+}
+
+/// Wait until the client gives up the request
+#[derive(Clone)]
+struct Abandoned {
+    timer: Timer,
+    calls: Arc<AtomicUsize>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl EchoService for Abandoned {
+    type EchoFuture = Box<Future<Item = (Simple, Controller), Error = MethodError>>;
+
+    fn echo(&self, (msg, controller): (Simple, Controller)) -> Self::EchoFuture {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        let timer = self.timer.clone();
+        let cancelled = self.cancelled.clone();
+        let fut = future::loop_fn(controller, move |controller| {
+            timer
+                .sleep(Duration::from_millis(200))
+                .then(move |_| {
+                    if controller.is_cancelled() {
+                        Ok(Loop::Break(controller))
+                    } else {
+                        Ok(Loop::Continue(controller))
+                    }
+                })
+        }).map(move |controller| {
+            cancelled.store(true, Ordering::SeqCst);
+            (msg, controller)
+        });
+        Box::new(fut)
+    }
+}
+
+#[test]
+fn cancel_dropped_request() {
+    let addr = "127.0.0.1:9027";
+    let calls = Arc::new(AtomicUsize::new(0));
+    let cancelled = Arc::new(AtomicBool::new(false));
+    start_server(
+        addr,
+        Abandoned {
+            timer: Timer::default(),
+            calls: calls.clone(),
+            cancelled: cancelled.clone(),
+        },
+    );
+    let mut core = Core::new().unwrap();
+    let timer = Timer::default();
+    let msg = simple(10, true, "HelloWorld");
+
+    let builder = ChannelBuilder::single_server(addr, core.handle()).notify_cancel(true);
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    // give up the request once the server is working on it
+    let call = stub.echo(msg.clone());
+    match core.run(call.select2(timer.sleep(Duration::from_millis(300)))) {
+        Err(Either::B((_, call))) => drop(call),
+        Ok(Either::B((_, call))) => drop(call),
+        _ => panic!("the request is answered"),
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    core.run(timer.sleep(Duration::from_millis(600))).unwrap();
+    assert!(cancelled.load(Ordering::SeqCst));
+
+    // a request dropped before it is sent never reaches the server
+    drop(stub.echo(msg.clone()));
+    core.run(timer.sleep(Duration::from_millis(300))).unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+/// Start a server that answers every frame after `delay`, as the servers that
+/// do not know the cancel notice do.
+fn start_naive_server(addr: &'static str, delay: Duration) {
+    let listener = TcpListener::bind(addr).unwrap();
+    spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            spawn(move || {
+                let mut proto = BrpcProtocol::new();
+                let mut buf = BytesMut::new();
+                let mut chunk = [0; 1024];
+                loop {
+                    if let Ok((id, (_, controller, body))) = proto.try_parse(&mut buf) {
+                        sleep(delay);
+                        let mut meta = RpcMeta::new();
+                        meta.set_correlation_id(id);
+                        meta.set_response(RpcResponseMeta::new());
+                        let mut package = BytesMut::new();
+                        proto
+                            .write_package((meta, controller, body), &mut package)
+                            .unwrap();
+                        if stream.write_all(&package).is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                    match stream.read(&mut chunk) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn drop_request_without_cancel_notice() {
+    let addr = "127.0.0.1:9028";
+    start_naive_server(addr, Duration::from_millis(400));
+    let mut core = Core::new().unwrap();
+    let timer = Timer::default();
+    let msg = simple(10, true, "HelloWorld");
+
+    let builder = ChannelBuilder::single_server(addr, core.handle()).max_retry(0);
+    let channel = core.run(builder.build()).unwrap();
+    let stub = EchoStub::new(&channel);
+
+    match core.run(stub.echo(msg.clone()).select2(timer.sleep(Duration::from_millis(200)))) {
+        Err(Either::B((_, call))) => drop(call),
+        Ok(Either::B((_, call))) => drop(call),
+        _ => panic!("the request is answered"),
+    }
+    // the late response is discarded, and the connection is still usable
+    let (resp, _info) = core.run(stub.echo(msg.clone())).unwrap();
+    assert_eq!(resp, msg);
+}
@@ -97,8 +97,21 @@ impl<'a> EchoStub<'a> {
             super::simple::Simple,
             super::simple::Simple,
         >,
+    > {
+        self.echo_with_options(msg, ::copra::channel::CallOptions::default())
+    }
+
+    pub fn echo_with_options(
+        &'a self, 
+        msg: super::simple::Simple,
+        options: ::copra::channel::CallOptions,
+    ) -> ::copra::stub::StubFuture<
+        ::copra::codec::ProtobufCodec<
+            super::simple::Simple,
+            super::simple::Simple,
+        >,
     > {
         self.echo_wrapper
-            .call((msg, "Echo".to_string(), "echo".to_string()))
+            .call((msg, "Echo".to_string(), "echo".to_string()), options)
     }
 }
@@ -84,12 +84,25 @@ impl<'a> {}<'a> {{
             {},
             {},
         >,
+    > {{
+        self.{}_with_options(msg, ::copra::channel::CallOptions::default())
+    }}
+
+    pub fn {}_with_options(
+        &'a self, 
+        msg: {},
+        options: ::copra::channel::CallOptions,
+    ) -> ::copra::stub::StubFuture<
+        ::copra::codec::ProtobufCodec<
+            {},
+            {},
+        >,
     > {{
         self.{}
-            .call((msg, "{}".to_string(), "{}".to_string()))
+            .call((msg, "{}".to_string(), "{}".to_string()), options)
     }}
 "#,
-                method, req, resp, req, wrap, service_name, method
+                method, req, resp, req, method, method, req, resp, req, wrap, service_name, method
             );
     }
 